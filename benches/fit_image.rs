@@ -0,0 +1,128 @@
+//! Times Lorentzian fits of whole images, with the linear and a robust
+//! loss, against a reference of the earlier image loop. The reference
+//! clones the sweep axis and the trace of every pixel, fits it in a fresh
+//! workspace and stores the result in maps shared behind one `Mutex`.
+//! Both run in a pool of `THREADS` rayon threads, also on machines with
+//! fewer cores.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use nalgebra::DVector;
+use ndarray::{s, Array1, Array2, Array3, Array5};
+use qufit::fit_common::ImageFit;
+use qufit::fit_esr_nalgebra::Lorentzian;
+use qufit::fit_loss::{Loss, RobustLoss};
+use qufit::fit_model::{fit_trace, Constraints, FitModel, FitSettings};
+use qufit::load::DataContainer;
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use std::sync::Mutex;
+
+const N_POINTS: usize = 100;
+const THREADS: usize = 4;
+
+/// ESR image of `size` x `size` pixels with slightly shifted resonances
+/// and a deterministic ripple in place of noise.
+fn esr_image(size: usize) -> DataContainer {
+    let x = Array1::linspace(0.0, 1.0, N_POINTS);
+    let mut data = Array5::zeros((1, N_POINTS, 1, size, size));
+    for i in 0..size {
+        for j in 0..size {
+            let n = (i * size + j) as f64;
+            let x0 = 0.4 + 0.2 * n / (size * size) as f64;
+            let truth = DVector::from_vec(vec![0.01, 0.05, x0]);
+            let trace = x.mapv(|x| Lorentzian.evaluate(x, &truth) + 1e-3 * (97.0 * x + n).sin());
+            data.slice_mut(s![0, .., 0, i, j]).assign(&trace);
+        }
+    }
+    DataContainer::from_data(data.into_dyn())
+}
+
+fn settings(loss: Loss) -> FitSettings {
+    FitSettings {
+        constraints: Constraints::new(&Lorentzian),
+        loss: RobustLoss {
+            loss,
+            f_scale: None,
+        },
+        coarse_bin: None,
+    }
+}
+
+/// The image loop before per-thread workspaces: every pixel allocates its
+/// own axis, trace and fit vectors, and all rows share one lock.
+fn mutex_reference(container: &DataContainer, settings: &FitSettings) -> ImageFit {
+    let x_axis = container.sweep_axis(1).unwrap();
+    let traces = container.data.slice(s![0, .., 0, .., ..]);
+    let (_, xdim, ydim) = traces.dim();
+    let n_params = Lorentzian.n_params();
+    let maps = Mutex::new(ImageFit {
+        x_axis: x_axis.clone(),
+        params: Array3::zeros((xdim, ydim, n_params)),
+        errors: Array3::zeros((xdim, ydim, n_params)),
+        status: Array2::zeros((xdim, ydim)),
+        n_evaluations: Array2::zeros((xdim, ydim)),
+        reduced_chi2: Array2::zeros((xdim, ydim)),
+        r_squared: Array2::zeros((xdim, ydim)),
+        rms: Array2::zeros((xdim, ydim)),
+    });
+    (0..xdim).into_par_iter().for_each(|i| {
+        for j in 0..ydim {
+            let x = x_axis.clone();
+            let y = traces.slice(s![.., i, j]).to_owned();
+            let pixel = fit_trace(
+                &Lorentzian,
+                &settings.constraints,
+                &settings.loss,
+                &x,
+                &y,
+                None,
+            );
+            let mut maps = maps.lock().unwrap();
+            maps.params.slice_mut(s![i, j, ..]).assign(&pixel.params);
+            maps.errors.slice_mut(s![i, j, ..]).assign(&pixel.errors);
+            maps.status[[i, j]] = pixel.status as u8;
+            maps.n_evaluations[[i, j]] = pixel.n_evaluations;
+            maps.reduced_chi2[[i, j]] = pixel.reduced_chi2;
+            maps.r_squared[[i, j]] = pixel.r_squared;
+            maps.rms[[i, j]] = pixel.rms;
+        }
+    });
+    maps.into_inner().unwrap()
+}
+
+fn lorentzian_image(c: &mut Criterion) {
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(THREADS)
+        .build()
+        .unwrap();
+    let mut group = c.benchmark_group("lorentzian_image");
+    group.sample_size(10);
+    for (size, name, loss) in [
+        (64, "linear", Loss::Linear),
+        (64, "cauchy", Loss::Cauchy),
+        (256, "linear", Loss::Linear),
+    ] {
+        let container = esr_image(size);
+        let settings = settings(loss);
+        group.bench_with_input(
+            BenchmarkId::new(format!("{}/workspaces", name), size),
+            &container,
+            |b, container| {
+                b.iter(|| {
+                    pool.install(|| {
+                        container.fit_model_image(&Lorentzian, &settings, 1, None, None)
+                    })
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new(format!("{}/mutex_reference", name), size),
+            &container,
+            |b, container| b.iter(|| pool.install(|| mutex_reference(container, &settings))),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, lorentzian_image);
+criterion_main!(benches);
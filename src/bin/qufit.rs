@@ -0,0 +1,448 @@
+//! Batch processing of QuPyt measurements without a Python environment.
+//!
+//! Every input is loaded together with its metadata and axis files,
+//! normalized to the reference, binned, median filtered and fitted, or run
+//! through a pipeline file. The parameter maps are written as .npy files to
+//! one folder per measurement, or as one .npz file per measurement, with a
+//! JSON header describing the fit and the processing history.
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use qufit::fit_esr_nalgebra::EsrModel;
+use qufit::fit_loss::Loss;
+use qufit::fit_rabi_nalgebra::{Envelope, GuessStrategy};
+use qufit::pipeline::{Bound, FitConfig, Step};
+use qufit::{DataContainer, FitResult, Pipeline, QufitError};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::str::FromStr;
+
+#[derive(Parser)]
+#[command(
+    name = "qufit",
+    version,
+    about = "Fit QuPyt measurements pixel by pixel"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// ESR lineshape along the first sweep axis.
+    Esr {
+        #[command(flatten)]
+        run: Run,
+        #[arg(long, default_value = "lorentzian", value_parser = checked::<EsrModel>)]
+        model: String,
+        /// Unit of the sweep axis, if the metadata does not give it. The
+        /// hyperfine models need Hz, kHz, MHz or GHz.
+        #[arg(long)]
+        axis_unit: Option<String>,
+    },
+    /// Several Lorentzian dips along the first sweep axis.
+    EsrMulti {
+        #[command(flatten)]
+        run: Run,
+        #[arg(long)]
+        n_peaks: usize,
+        /// Fit one linewidth for all dips.
+        #[arg(long)]
+        shared_width: bool,
+        #[arg(long)]
+        axis_unit: Option<String>,
+    },
+    /// Rabi oscillation along the second sweep axis.
+    Rabi {
+        #[command(flatten)]
+        run: Run,
+        /// exponential, gaussian or stretched. Undamped if not given.
+        #[arg(long, value_parser = checked::<Envelope>)]
+        damping: Option<String>,
+        /// Starting point estimate: fft or argmin.
+        #[arg(long, default_value = "fft", value_parser = checked::<GuessStrategy>)]
+        guess: String,
+        #[arg(long)]
+        axis_unit: Option<String>,
+    },
+    /// Stretched exponential decay along the second sweep axis.
+    T1 {
+        #[command(flatten)]
+        run: Run,
+        #[arg(long)]
+        axis_unit: Option<String>,
+    },
+    /// Runs the steps of a TOML, YAML or JSON pipeline file, which has to
+    /// end in a fit.
+    Pipeline {
+        config: PathBuf,
+        #[command(flatten)]
+        io: Io,
+    },
+}
+
+#[derive(Args)]
+struct Io {
+    /// .npy files, or folders whose .npy files are all processed.
+    #[arg(required = true)]
+    inputs: Vec<PathBuf>,
+    /// Folder for the results, with one subfolder per measurement.
+    #[arg(short, long, default_value = "qufit_results")]
+    output: PathBuf,
+    /// Bundle the maps of every measurement into one .npz file instead.
+    #[arg(long)]
+    npz: bool,
+}
+
+/// Inputs, preprocessing and fit settings shared by all fits.
+#[derive(Args)]
+struct Run {
+    #[command(flatten)]
+    io: Io,
+    /// Normalization to the reference along the first axis. The ESR models
+    /// expect the baseline of 1 of `ratio`.
+    #[arg(long, value_enum, default_value_t = Reference::Ratio)]
+    reference: Reference,
+    /// Average blocks of N x N pixels.
+    #[arg(long, value_name = "N")]
+    bin: Option<usize>,
+    /// Median filter every image with an N x N kernel.
+    #[arg(long, value_name = "N")]
+    medfilt: Option<usize>,
+    /// Weight the residuals with the shot noise of the raw counts.
+    #[arg(long)]
+    shot_noise: bool,
+    /// linear, huber, soft_l1 or cauchy.
+    #[arg(long, default_value = "linear", value_parser = checked::<Loss>)]
+    loss: String,
+    #[arg(long)]
+    f_scale: Option<f64>,
+    /// Start every pixel from a fit of N x N pixel blocks.
+    #[arg(long, value_name = "N")]
+    coarse_bin: Option<usize>,
+    /// Hold a parameter at a value, e.g. `--fix gamma=0.5`.
+    #[arg(long, value_name = "NAME=VALUE", value_parser = parse_fixed)]
+    fix: Vec<(String, f64)>,
+    /// Bound a parameter, e.g. `--bound x0=2860:2880`. Leave a side empty
+    /// to keep it open. Widths and times stay positive in any case.
+    #[arg(long, value_name = "NAME=LOWER:UPPER", value_parser = parse_bound)]
+    bound: Vec<(String, Bound)>,
+    /// Parameters common to the whole field of view.
+    #[arg(long, value_delimiter = ',')]
+    shared: Vec<String>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Reference {
+    Sum,
+    Ratio,
+    /// The data is already normalized.
+    None,
+}
+
+/// Accepts `arg` if it names a `T`.
+fn checked<T: FromStr<Err = String>>(arg: &str) -> Result<String, String> {
+    T::from_str(arg)?;
+    Ok(arg.to_string())
+}
+
+fn parse_fixed(arg: &str) -> Result<(String, f64), String> {
+    let (name, value) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("Expected NAME=VALUE, got '{}'", arg))?;
+    let value = value
+        .parse()
+        .map_err(|_| format!("'{}' is not a number", value))?;
+    Ok((name.to_string(), value))
+}
+
+fn parse_bound(arg: &str) -> Result<(String, Bound), String> {
+    let (name, range) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("Expected NAME=LOWER:UPPER, got '{}'", arg))?;
+    let (lower, upper) = range
+        .split_once(':')
+        .ok_or_else(|| format!("Expected LOWER:UPPER, got '{}'", range))?;
+    let side = |value: &str| match value.trim() {
+        "" => Ok(None),
+        value => value
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("'{}' is not a number", value)),
+    };
+    Ok((
+        name.to_string(),
+        Bound {
+            lower: side(lower)?,
+            upper: side(upper)?,
+        },
+    ))
+}
+
+impl Io {
+    /// The input files, with folders expanded to the measurements they
+    /// contain. Axis files are skipped.
+    fn files(&self) -> Result<Vec<PathBuf>, QufitError> {
+        let mut files = Vec::new();
+        for input in &self.inputs {
+            if !input.is_dir() {
+                files.push(input.clone());
+                continue;
+            }
+            let entries = fs::read_dir(input).map_err(|e| {
+                QufitError::Io(format!("Could not read {}: {}", input.display(), e))
+            })?;
+            let mut measurements: Vec<PathBuf> = entries
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .filter(|path| is_measurement(path))
+                .collect();
+            measurements.sort();
+            files.extend(measurements);
+        }
+        Ok(files)
+    }
+
+    /// The folder, or .npz file, that the maps of `path` are written to.
+    fn output(&self, path: &Path) -> PathBuf {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        if self.npz {
+            self.output.join(format!("{}.npz", stem))
+        } else {
+            self.output.join(&*stem)
+        }
+    }
+
+    /// Refuses inputs that would overwrite each other's results, such as
+    /// measurements of the same name in different folders.
+    fn check_outputs(&self, files: &[PathBuf]) -> Result<(), QufitError> {
+        let mut seen: HashMap<PathBuf, &Path> = HashMap::new();
+        for path in files {
+            if let Some(other) = seen.insert(self.output(path), path) {
+                return Err(QufitError::Value(format!(
+                    "{} and {} would both be written to {}",
+                    other.display(),
+                    path.display(),
+                    self.output(path).display()
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Run {
+    fn fit_config(&self) -> FitConfig {
+        FitConfig {
+            bounds: self.bound.iter().cloned().collect(),
+            fixed: self.fix.iter().cloned().collect(),
+            sigma: self.shot_noise.then(|| "shot_noise".to_string()),
+            loss: self.loss.clone(),
+            f_scale: self.f_scale,
+            coarse_bin: self.coarse_bin,
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// The preprocessing steps followed by `fit`.
+    fn pipeline(&self, fit: Step) -> Pipeline {
+        let mut steps = Vec::new();
+        match self.reference {
+            Reference::Sum => steps.push(Step::ReferenceSum),
+            Reference::Ratio => steps.push(Step::ReferenceRatio),
+            Reference::None => {}
+        }
+        if let Some(stepsize) = self.bin {
+            steps.push(Step::CompressData { stepsize });
+        }
+        if let Some(kernel_size) = self.medfilt {
+            steps.push(Step::Medfilt { kernel_size });
+        }
+        steps.push(fit);
+        Pipeline { steps }
+    }
+}
+
+/// A .npy file that is not the axis file `<stem>_axis<dim>.npy` of another
+/// measurement.
+fn is_measurement(path: &Path) -> bool {
+    if path.extension().and_then(|ext| ext.to_str()) != Some("npy") {
+        return false;
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    !stem
+        .rsplit_once("_axis")
+        .is_some_and(|(_, dim)| !dim.is_empty() && dim.chars().all(|c| c.is_ascii_digit()))
+}
+
+impl Command {
+    fn io(&self) -> &Io {
+        match self {
+            Command::Esr { run, .. }
+            | Command::EsrMulti { run, .. }
+            | Command::Rabi { run, .. }
+            | Command::T1 { run, .. } => &run.io,
+            Command::Pipeline { io, .. } => io,
+        }
+    }
+
+    fn pipeline(&self) -> Result<Pipeline, QufitError> {
+        let pipeline = match self {
+            Command::Esr {
+                run,
+                model,
+                axis_unit,
+            } => run.pipeline(Step::EsrFit {
+                model: model.clone(),
+                axis_unit: axis_unit.clone(),
+                fit: run.fit_config(),
+            }),
+            Command::EsrMulti {
+                run,
+                n_peaks,
+                shared_width,
+                axis_unit,
+            } => run.pipeline(Step::EsrMultiFit {
+                n_peaks: *n_peaks,
+                shared_width: *shared_width,
+                axis_unit: axis_unit.clone(),
+                fit: run.fit_config(),
+            }),
+            Command::Rabi {
+                run,
+                damping,
+                guess,
+                axis_unit,
+            } => run.pipeline(Step::RabiFit {
+                damping: damping.clone(),
+                guess: guess.clone(),
+                axis_unit: axis_unit.clone(),
+                fit: run.fit_config(),
+            }),
+            Command::T1 { run, axis_unit } => run.pipeline(Step::T1Fit {
+                axis_unit: axis_unit.clone(),
+                fit: run.fit_config(),
+            }),
+            Command::Pipeline { config, .. } => Pipeline::load(config)?,
+        };
+        if !pipeline.steps.last().is_some_and(Step::is_fit) {
+            return Err(QufitError::Value(
+                "The pipeline has to end in a fit".to_string(),
+            ));
+        }
+        pipeline.check()?;
+        Ok(pipeline)
+    }
+}
+
+/// Loads `path`, runs `pipeline` on it and writes the maps to a folder or
+/// .npz file named after the measurement. Folders also get the pipeline
+/// that produced the maps, ready to be replayed.
+fn process(path: &Path, pipeline: &Pipeline, io: &Io) -> Result<FitResult, QufitError> {
+    let path_str = path
+        .to_str()
+        .ok_or_else(|| QufitError::Io(format!("{} is not a valid UTF-8 path", path.display())))?;
+    let mut container = DataContainer::load(path_str)?;
+    let result = container
+        .run_pipeline(pipeline)?
+        .ok_or_else(|| QufitError::Value("The pipeline has to end in a fit".to_string()))?;
+    let output = io.output(path);
+    if io.npz {
+        fs::create_dir_all(&io.output).map_err(|e| {
+            QufitError::Io(format!("Could not create {}: {}", io.output.display(), e))
+        })?;
+        result.save_npz(&output)?;
+    } else {
+        result.save_npy(&output)?;
+        result.history.save(&output.join("pipeline.toml"))?;
+    }
+    Ok(result)
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let io = cli.command.io();
+    let setup = cli.command.pipeline().and_then(|pipeline| {
+        let files = io.files()?;
+        io.check_outputs(&files)?;
+        Ok((pipeline, files))
+    });
+    let (pipeline, files) = match setup {
+        Ok(setup) => setup,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut failed = 0;
+    for path in &files {
+        match process(path, &pipeline, io) {
+            Ok(result) => println!("{}: {}", path.display(), result),
+            Err(e) => {
+                eprintln!("{}: error: {}", path.display(), e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        eprintln!("{} of {} measurements failed", failed, files.len());
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_fix_and_bound() {
+        assert_eq!(parse_fixed("gamma=0.5"), Ok(("gamma".to_string(), 0.5)));
+        assert!(parse_fixed("gamma").is_err());
+        let bound = |lower, upper| Bound { lower, upper };
+        assert_eq!(
+            parse_bound("x0=2860:"),
+            Ok(("x0".to_string(), bound(Some(2860.0), None)))
+        );
+        assert_eq!(
+            parse_bound("x0=:1e3"),
+            Ok(("x0".to_string(), bound(None, Some(1000.0))))
+        );
+        assert!(parse_bound("x0=1").is_err());
+    }
+
+    #[test]
+    fn test_axis_files_are_not_measurements() {
+        assert!(is_measurement(Path::new("odmr.npy")));
+        assert!(is_measurement(Path::new("odmr_axis.npy")));
+        assert!(!is_measurement(Path::new("odmr_axis3.npy")));
+        assert!(!is_measurement(Path::new("odmr.yaml")));
+    }
+
+    #[test]
+    fn test_outputs_keep_dotted_names_and_do_not_collide() {
+        let io = |npz| Io {
+            inputs: Vec::new(),
+            output: PathBuf::from("out"),
+            npz,
+        };
+        assert_eq!(
+            io(true).output(Path::new("scan.2.87GHz.npy")),
+            Path::new("out/scan.2.87GHz.npz")
+        );
+        assert_eq!(
+            io(false).output(Path::new("scan.2.87GHz.npy")),
+            Path::new("out/scan.2.87GHz")
+        );
+        let distinct = [PathBuf::from("a/scan.1.npy"), PathBuf::from("a/scan.2.npy")];
+        assert!(io(true).check_outputs(&distinct).is_ok());
+        let same_name = [PathBuf::from("a/scan.npy"), PathBuf::from("b/scan.npy")];
+        for npz in [false, true] {
+            assert!(matches!(
+                io(npz).check_outputs(&same_name),
+                Err(QufitError::Value(_))
+            ));
+        }
+    }
+}
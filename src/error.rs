@@ -0,0 +1,32 @@
+use std::fmt;
+
+/// Errors of loading, preprocessing and fitting. Raised in Python as
+/// `OSError`, `ShapeError`, `DtypeError`, `FitError` and `ValueError`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum QufitError {
+    /// A file could not be read or parsed.
+    Io(String),
+    /// The data or an array argument has the wrong number of dimensions
+    /// or points.
+    Shape(String),
+    /// A file holds a numeric type that cannot be read.
+    Dtype(String),
+    /// A fit cannot be set up, e.g. with contradicting bounds or too few points.
+    Fit(String),
+    /// An argument is unknown or out of range.
+    Value(String),
+}
+
+impl fmt::Display for QufitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QufitError::Io(message)
+            | QufitError::Shape(message)
+            | QufitError::Dtype(message)
+            | QufitError::Fit(message)
+            | QufitError::Value(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for QufitError {}
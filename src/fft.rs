@@ -1,9 +1,9 @@
+use crate::error::QufitError;
 use crate::load::DataContainer;
 use hilbert_transform::hilbert;
-use ndarray::{s, Array1, Array4, Array5, Axis, Dim};
-use ndrustfft::{ndfft_r2c_par, Complex, R2cFftHandler};
+use ndarray::{s, Array1, Array4, Array5, Axis};
+use ndrustfft::{ndfft_r2c, ndfft_r2c_par, Complex, R2cFftHandler};
 use rayon::prelude::*;
-use std::sync::Mutex;
 
 // When benchmarkding this reference function with criterion,
 // the below option was the fastest by large margin. Needs to
@@ -19,68 +19,128 @@ use std::sync::Mutex;
 // }
 
 impl DataContainer {
-    pub fn array_fft(&self) -> Array4<Complex<f64>> {
-        let dims: Vec<usize> = self.data.shape().iter().cloned().collect(); // this might be really
-                                                                            // unecessary
-        match dims.len() {
-            5 => {
-                dbg!(&dims);
-                let mut vhat =
-                    Array4::<Complex<f64>>::zeros((dims[1], dims[2] / 2 + 1, dims[3], dims[4]));
-                let mut vhat = vhat.view_mut().into_dyn();
-                let mut handler = R2cFftHandler::<f64>::new(dims[2]);
-                let data = self.data.index_axis(Axis(0), 0);
-                ndfft_r2c_par(&data, &mut vhat, &mut handler, 1);
-                vhat.to_owned()
-                    .into_dimensionality::<Dim<[usize; 4]>>()
-                    .expect("Could not transform fft output into correct size array")
+    /// Spectrum along the second sweep axis, with the shape
+    /// `(sweep_1, frequencies, x, y)`. For 4D data the third axis has
+    /// length 1.
+    pub fn array_fft(&self) -> Result<Array4<Complex<f64>>, QufitError> {
+        let data = match self.data.ndim() {
+            5 => self.data.index_axis(Axis(0), 0),
+            4 => self.data.index_axis(Axis(0), 0).insert_axis(Axis(2)),
+            _ => {
+                return Err(QufitError::Shape(format!(
+                    "fft needs data with 4 or 5 dimensions, but the data has shape {:?}",
+                    self.data.shape()
+                )))
             }
-            4 => {
-                let mut vhat =
-                    Array4::<Complex<f64>>::zeros((dims[1], dims[2] / 2 + 1, 1, dims[3]));
-                let mut vhat = vhat.view_mut().into_dyn();
-                let mut handler = R2cFftHandler::<f64>::new(dims[2]);
-                let data = self.data.index_axis(Axis(0), 0);
-                ndfft_r2c_par(&data, &mut vhat, &mut handler, 1);
-                vhat.to_owned()
-                    .into_dimensionality::<Dim<[usize; 4]>>()
-                    .expect("Could not transform fft output into correct size array")
-            }
-            _ => unimplemented!("Your input has an unsupported number of dimensions (4, 5)"),
+        };
+        let dims = data.shape().to_vec();
+        let mut vhat = Array4::<Complex<f64>>::zeros((dims[0], dims[1] / 2 + 1, dims[2], dims[3]));
+        let handler = R2cFftHandler::<f64>::new(dims[1]);
+        ndfft_r2c_par(&data, &mut vhat.view_mut().into_dyn(), &handler, 1);
+        Ok(vhat)
+    }
+
+    /// Frequencies belonging to the second axis of `array_fft`, in inverse
+    /// units of the sweep axis (e.g. GHz for a sweep in ns). Fails if the
+    /// axis has no extent.
+    pub fn fft_frequencies(&self) -> Result<Array1<f64>, QufitError> {
+        if self.data.ndim() < 3 {
+            return Err(QufitError::Shape(format!(
+                "The data has shape {:?} and no second sweep axis",
+                self.data.shape()
+            )));
         }
+        let x_axis = self.sweep_axis(2)?;
+        let n = x_axis.len();
+        let spacing = if n > 1 {
+            (x_axis[n - 1] - x_axis[0]) / (n - 1) as f64
+        } else {
+            1.0
+        };
+        if spacing == 0.0 || !spacing.is_finite() {
+            return Err(QufitError::Value(format!(
+                "The second sweep axis runs from {} to {}, its spacing gives no frequencies",
+                x_axis[0],
+                x_axis[n - 1]
+            )));
+        }
+        Ok(Array1::from_iter(
+            (0..n / 2 + 1).map(|k| k as f64 / (n as f64 * spacing)),
+        ))
     }
 }
 
-impl DataContainer {
-    pub fn array_hilbert(&self) -> Array5<Complex<f64>> {
-        let dims: Vec<usize> = self.data.shape().iter().cloned().collect(); // this might be really
-                                                                            // unecessary
-        match dims.len() {
-            5 => {
-                dbg!(&dims);
-                let vhat =
-                    Array5::<Complex<f64>>::zeros((dims[0], dims[1], dims[2], dims[3], dims[4]));
-                let vhat_mutex = Mutex::new(vhat);
-                (0..dims[3]).into_par_iter().for_each(|i| {
-                    for j in 0..dims[4] {
-                        let out = hilbert(
-                            self.data
-                                .slice(s![0, 0, .., i, j])
-                                .to_owned()
-                                .as_slice()
-                                .unwrap(),
-                        );
-                        let mut vhat_unlock = vhat_mutex.lock().unwrap();
-                        vhat_unlock
-                            .slice_mut(s![0, 0, .., i, j])
-                            .assign(&Array1::<Complex<f64>>::from_vec(out));
-                    }
-                });
+/// Strongest oscillation in `trace`, sampled on the equidistant `x_axis`.
+/// Returns `(frequency, amplitude, phase)` with the frequency in inverse
+/// units of `x_axis` and the phase relative to the first sample, i.e.
+/// `trace ~ amplitude * cos(2 pi frequency (x - x_axis[0]) + phase)`.
+/// The DC bin is skipped and the frequency is at least one period over
+/// the trace, so slower oscillations are reported at that limit. The axis
+/// may be descending. With fewer than two samples or an axis without extent
+/// there is no frequency to measure, and one period per axis unit without
+/// amplitude is returned.
+pub fn dominant_oscillation(x_axis: &Array1<f64>, trace: &Array1<f64>) -> (f64, f64, f64) {
+    let n = trace.len();
+    let span = if n > 1 {
+        x_axis[n - 1] - x_axis[0]
+    } else {
+        0.0
+    };
+    if span == 0.0 || !span.is_finite() {
+        return (1.0, 0.0, 0.0);
+    }
+    let centered = trace - trace.mean().unwrap_or(0.0);
+    let mut spectrum = Array1::<Complex<f64>>::zeros(n / 2 + 1);
+    let handler = R2cFftHandler::<f64>::new(n);
+    ndfft_r2c(&centered, &mut spectrum, &handler, 0);
 
-                let vhat = vhat_mutex.into_inner().unwrap();
-                vhat
-            }
-            _ => unimplemented!("Your input has an unsupported number of dimensions (4, 5)"),
+    let magnitude = spectrum.mapv(|c| c.norm());
+    let k = (1..magnitude.len())
+        .max_by(|&a, &b| magnitude[a].total_cmp(&magnitude[b]))
+        .unwrap_or(0);
+    // Parabolic interpolation between the neighbouring bins gives a
+    // frequency estimate below the bin spacing. The DC bin is not a
+    // neighbour, it only holds what is left of the mean.
+    let shift = if k > 1 && k + 1 < magnitude.len() {
+        let (left, center, right) = (magnitude[k - 1], magnitude[k], magnitude[k + 1]);
+        let denom = left - 2.0 * center + right;
+        if denom != 0.0 {
+            0.5 * (left - right) / denom
+        } else {
+            0.0
+        }
+    } else {
+        0.0
+    };
+    let spacing = span.abs() / (n - 1) as f64;
+    let frequency = ((k as f64 + shift) / (n as f64 * spacing)).max(1.0 / span.abs());
+    let amplitude = 2.0 * magnitude[k] / n as f64;
+    // On a descending axis x - x_axis[0] runs backwards through the
+    // samples, which mirrors the phase.
+    (frequency, amplitude, span.signum() * spectrum[k].arg())
+}
+
+impl DataContainer {
+    /// Analytic signal of every trace along the second sweep axis.
+    pub fn array_hilbert(&self) -> Result<Array5<Complex<f64>>, QufitError> {
+        if self.data.ndim() != 5 {
+            return Err(QufitError::Shape(format!(
+                "hilbert needs data with 5 dimensions, but the data has shape {:?}",
+                self.data.shape()
+            )));
         }
+        let dims = self.data.shape();
+        let mut vhat = Array5::<Complex<f64>>::zeros((dims[0], dims[1], dims[2], dims[3], dims[4]));
+        vhat.slice_mut(s![0, 0, .., .., ..])
+            .axis_iter_mut(Axis(1))
+            .into_par_iter()
+            .enumerate()
+            .for_each(|(i, mut traces)| {
+                for (j, mut trace) in traces.axis_iter_mut(Axis(1)).enumerate() {
+                    let out = hilbert(&self.data.slice(s![0, 0, .., i, j]).to_vec());
+                    trace.assign(&Array1::from_vec(out));
+                }
+            });
+        Ok(vhat)
     }
 }
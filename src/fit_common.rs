@@ -0,0 +1,495 @@
+use crate::error::QufitError;
+use crate::load::DataContainer;
+use levenberg_marquardt::{LeastSquaresProblem, LevenbergMarquardt, TerminationReason};
+use nalgebra::{DVector, Dyn, Owned};
+use ndarray::{
+    s, Array1, Array2, Array3, ArrayD, ArrayView1, ArrayView3, ArrayViewMut1, ArrayViewMut2, Axis,
+    Ix3,
+};
+use rayon::prelude::*;
+
+/// Why the optimizer stopped. Stored as `u8` in the status map of an
+/// [`ImageFit`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum FitStatus {
+    /// The `ftol` or `xtol` criterion was fulfilled.
+    Converged = 0,
+    /// The residuals are exactly zero.
+    ResidualsZero = 1,
+    /// The residuals are orthogonal to the Jacobian columns (`gtol`).
+    Orthogonal = 2,
+    /// The maximum number of evaluations was reached.
+    LostPatience = 3,
+    /// The tolerances are below machine precision.
+    NoImprovementPossible = 4,
+    /// The residuals or the Jacobian contained NaN or infinity.
+    Numerical = 5,
+    /// The problem was malformed, e.g. fewer points than parameters.
+    Invalid = 6,
+}
+
+impl FitStatus {
+    pub const NAMES: [&'static str; 7] = [
+        "converged",
+        "residuals_zero",
+        "orthogonal",
+        "lost_patience",
+        "no_improvement_possible",
+        "numerical",
+        "invalid",
+    ];
+
+    /// `Converged`, `ResidualsZero` or `Orthogonal`.
+    pub fn is_success(self) -> bool {
+        self <= FitStatus::Orthogonal
+    }
+}
+
+impl TryFrom<u8> for FitStatus {
+    type Error = u8;
+
+    /// The status stored in the status map of an [`ImageFit`].
+    fn try_from(code: u8) -> Result<Self, u8> {
+        Ok(match code {
+            0 => FitStatus::Converged,
+            1 => FitStatus::ResidualsZero,
+            2 => FitStatus::Orthogonal,
+            3 => FitStatus::LostPatience,
+            4 => FitStatus::NoImprovementPossible,
+            5 => FitStatus::Numerical,
+            6 => FitStatus::Invalid,
+            _ => return Err(code),
+        })
+    }
+}
+
+impl From<&TerminationReason> for FitStatus {
+    fn from(reason: &TerminationReason) -> Self {
+        match reason {
+            TerminationReason::Converged { .. } => FitStatus::Converged,
+            TerminationReason::ResidualsZero => FitStatus::ResidualsZero,
+            TerminationReason::Orthogonal => FitStatus::Orthogonal,
+            TerminationReason::LostPatience => FitStatus::LostPatience,
+            TerminationReason::NoImprovementPossible(_) => FitStatus::NoImprovementPossible,
+            TerminationReason::Numerical(_) => FitStatus::Numerical,
+            TerminationReason::User(_)
+            | TerminationReason::NoParameters
+            | TerminationReason::NoResiduals
+            | TerminationReason::WrongDimensions(_) => FitStatus::Invalid,
+        }
+    }
+}
+
+/// Best-fit parameters of a single pixel, their standard errors and how
+/// well the model describes the data.
+#[derive(Clone, Debug)]
+pub struct PixelFit {
+    pub params: Array1<f64>,
+    pub errors: Array1<f64>,
+    pub status: FitStatus,
+    /// Number of residual evaluations used by the optimizer.
+    pub n_evaluations: usize,
+    pub reduced_chi2: f64,
+    pub r_squared: f64,
+    pub rms: f64,
+}
+
+impl PixelFit {
+    /// Fills in the goodness of fit from the sum of squared (weighted)
+    /// residuals `ssr`, the measured trace and the weight of every point.
+    pub fn new(
+        params: Array1<f64>,
+        errors: Array1<f64>,
+        status: FitStatus,
+        n_evaluations: usize,
+        ssr: f64,
+        y_data: ArrayView1<f64>,
+        weights: ArrayView1<f64>,
+    ) -> Self {
+        let n_points = y_data.len() as f64;
+        let w2 = || weights.iter().map(|w| w * w);
+        let mean = w2().zip(&y_data).map(|(w2, y)| w2 * y).sum::<f64>() / w2().sum::<f64>();
+        let sst: f64 = w2()
+            .zip(&y_data)
+            .map(|(w2, y)| w2 * (y - mean).powi(2))
+            .sum();
+        let dof = n_points - params.len() as f64;
+        PixelFit {
+            reduced_chi2: if dof > 0.0 { ssr / dof } else { f64::NAN },
+            r_squared: 1.0 - ssr / sst,
+            rms: (ssr / n_points).sqrt(),
+            params,
+            errors,
+            status,
+            n_evaluations,
+        }
+    }
+}
+
+/// Parameter maps of an image fit. The last axis of `params` and `errors`
+/// indexes the fit parameters, the diagnostic maps have one value per pixel.
+#[derive(Clone, Debug)]
+pub struct ImageFit {
+    /// Sweep axis the traces were fitted against.
+    pub x_axis: Array1<f64>,
+    pub params: Array3<f64>,
+    pub errors: Array3<f64>,
+    pub status: Array2<u8>,
+    /// Residual evaluations per pixel. Levenberg-Marquardt does not report
+    /// its iterations, and every iteration takes one or more evaluations.
+    pub n_evaluations: Array2<usize>,
+    pub reduced_chi2: Array2<f64>,
+    pub r_squared: Array2<f64>,
+    pub rms: Array2<f64>,
+}
+
+impl ImageFit {
+    pub(crate) fn zeros(x_axis: Array1<f64>, xdim: usize, ydim: usize, n_params: usize) -> Self {
+        ImageFit {
+            x_axis,
+            params: Array3::zeros((xdim, ydim, n_params)),
+            errors: Array3::zeros((xdim, ydim, n_params)),
+            status: Array2::zeros((xdim, ydim)),
+            n_evaluations: Array2::zeros((xdim, ydim)),
+            reduced_chi2: Array2::zeros((xdim, ydim)),
+            r_squared: Array2::zeros((xdim, ydim)),
+            rms: Array2::zeros((xdim, ydim)),
+        }
+    }
+
+    /// Stores the fit of pixel `(i, j)`.
+    pub(crate) fn set(&mut self, (i, j): (usize, usize), fit: &PixelFit) {
+        self.params.slice_mut(s![i, j, ..]).assign(&fit.params);
+        self.errors.slice_mut(s![i, j, ..]).assign(&fit.errors);
+        self.status[[i, j]] = fit.status as u8;
+        self.n_evaluations[[i, j]] = fit.n_evaluations;
+        self.reduced_chi2[[i, j]] = fit.reduced_chi2;
+        self.r_squared[[i, j]] = fit.r_squared;
+        self.rms[[i, j]] = fit.rms;
+    }
+
+    /// True for every pixel that terminated with `Converged`,
+    /// `ResidualsZero` or `Orthogonal`.
+    pub fn success_mask(&self) -> Array2<bool> {
+        self.status.mapv(succeeded)
+    }
+
+    /// Whether the fit of pixel `(i, j)` succeeded, see
+    /// [`ImageFit::success_mask`].
+    pub fn is_success(&self, (i, j): (usize, usize)) -> bool {
+        succeeded(self.status[[i, j]])
+    }
+}
+
+fn succeeded(code: u8) -> bool {
+    FitStatus::try_from(code).is_ok_and(FitStatus::is_success)
+}
+
+/// Standard errors from the covariance matrix `(J^T J)^-1` of the weighted
+/// residuals. With `absolute_sigma` the weights are `1 / sigma` of known
+/// uncertainties and the covariance is returned as is. Otherwise it is
+/// scaled by the reduced chi-square of the residuals at the optimum, which
+/// estimates the noise from the scatter around the fit. Returns NaN if the
+/// covariance cannot be computed.
+pub fn standard_errors<P>(problem: &P, absolute_sigma: bool) -> Array1<f64>
+where
+    P: LeastSquaresProblem<
+        f64,
+        Dyn,
+        Dyn,
+        ParameterStorage = Owned<f64, Dyn>,
+        ResidualStorage = Owned<f64, Dyn>,
+        JacobianStorage = Owned<f64, Dyn, Dyn>,
+    >,
+{
+    let n_params = problem.params().len();
+    let nan = Array1::from_elem(n_params, f64::NAN);
+    let (Some(residuals), Some(jacobian)) = (problem.residuals(), problem.jacobian()) else {
+        return nan;
+    };
+    let dof = residuals.len() as f64 - n_params as f64;
+    if dof <= 0.0 {
+        return nan;
+    }
+    let scale = if absolute_sigma {
+        1.0
+    } else {
+        residuals.norm_squared() / dof
+    };
+    match (jacobian.transpose() * &jacobian).try_inverse() {
+        Some(covariance) => {
+            Array1::from_iter(covariance.diagonal().iter().map(|v| (v * scale).sqrt()))
+        }
+        None => nan,
+    }
+}
+
+/// Runs Levenberg-Marquardt from the parameters stored in `problem`.
+/// `y_data` is the measured trace and `weights` the weight of every point
+/// in the residuals, used for the goodness of fit. `absolute_sigma` is
+/// passed on to [`standard_errors`]. Pixels that do not converge keep the
+/// last iterate; check `status`.
+pub fn minimize<P>(
+    problem: P,
+    y_data: ArrayView1<f64>,
+    weights: ArrayView1<f64>,
+    absolute_sigma: bool,
+) -> PixelFit
+where
+    P: LeastSquaresProblem<
+        f64,
+        Dyn,
+        Dyn,
+        ParameterStorage = Owned<f64, Dyn>,
+        ResidualStorage = Owned<f64, Dyn>,
+        JacobianStorage = Owned<f64, Dyn, Dyn>,
+    >,
+{
+    let (result, report) = LevenbergMarquardt::new().minimize(problem);
+    let opt_params: DVector<f64> = result.params();
+    let ssr = result
+        .residuals()
+        .map_or(f64::NAN, |residuals| residuals.norm_squared());
+    PixelFit::new(
+        Array1::from_vec(opt_params.data.into()),
+        standard_errors(&result, absolute_sigma),
+        FitStatus::from(&report.termination),
+        report.number_of_evaluations,
+        ssr,
+        y_data,
+        weights,
+    )
+}
+
+/// Views `array` as traces along `sweep_dim` (1 or 2) with the pixels
+/// along the last two axes. The other leading axes are indexed at zero.
+pub(crate) fn pixel_traces(
+    array: &ArrayD<f64>,
+    sweep_dim: usize,
+) -> Result<ArrayView3<'_, f64>, QufitError> {
+    if !matches!(sweep_dim, 1 | 2) {
+        return Err(QufitError::Value(format!(
+            "Fits run along sweep_1 or sweep_2 (dimension 1 or 2), not along dimension {}",
+            sweep_dim
+        )));
+    }
+    if !matches!(array.ndim(), 4 | 5) {
+        return Err(QufitError::Shape(format!(
+            "Fits need data with 4 or 5 dimensions (reference, sweep_1, sweep_2, x[, y]), but the data has shape {:?}",
+            array.shape()
+        )));
+    }
+    let mut traces = array.view();
+    for dim in (0..3).rev() {
+        if dim != sweep_dim {
+            traces = traces.index_axis_move(Axis(dim), 0);
+        }
+    }
+    if traces.ndim() == 2 {
+        traces = traces.insert_axis(Axis(2));
+    }
+    traces
+        .into_dimensionality::<Ix3>()
+        .map_err(|e| QufitError::Shape(e.to_string()))
+}
+
+/// Mutable views of one row of the maps of an [`ImageFit`], so that rows
+/// can be filled in parallel without locking.
+struct RowFit<'a> {
+    params: ArrayViewMut2<'a, f64>,
+    errors: ArrayViewMut2<'a, f64>,
+    status: ArrayViewMut1<'a, u8>,
+    n_evaluations: ArrayViewMut1<'a, usize>,
+    reduced_chi2: ArrayViewMut1<'a, f64>,
+    r_squared: ArrayViewMut1<'a, f64>,
+    rms: ArrayViewMut1<'a, f64>,
+}
+
+impl RowFit<'_> {
+    fn set(&mut self, j: usize, fit: &PixelFit) {
+        self.params.row_mut(j).assign(&fit.params);
+        self.errors.row_mut(j).assign(&fit.errors);
+        self.status[j] = fit.status as u8;
+        self.n_evaluations[j] = fit.n_evaluations;
+        self.reduced_chi2[j] = fit.reduced_chi2;
+        self.r_squared[j] = fit.r_squared;
+        self.rms[j] = fit.rms;
+    }
+}
+
+impl ImageFit {
+    fn rows_mut(&mut self) -> Vec<RowFit<'_>> {
+        self.params
+            .outer_iter_mut()
+            .zip(self.errors.outer_iter_mut())
+            .zip(self.status.outer_iter_mut())
+            .zip(self.n_evaluations.outer_iter_mut())
+            .zip(self.reduced_chi2.outer_iter_mut())
+            .zip(self.r_squared.outer_iter_mut())
+            .zip(self.rms.outer_iter_mut())
+            .map(
+                |(
+                    (((((params, errors), status), n_evaluations), reduced_chi2), r_squared),
+                    rms,
+                )| {
+                    RowFit {
+                        params,
+                        errors,
+                        status,
+                        n_evaluations,
+                        reduced_chi2,
+                        r_squared,
+                        rms,
+                    }
+                },
+            )
+            .collect()
+    }
+}
+
+impl DataContainer {
+    /// Runs `fit_pixel` on the trace along `sweep_dim` (1 or 2) of every
+    /// pixel and stacks the results. The other leading axes are indexed at
+    /// zero. `fit_pixel` gets a workspace made by `init`, the pixel index,
+    /// the sweep axis, the trace and, if `sigma` is given, the matching
+    /// trace of standard deviations.
+    ///
+    /// Rows of pixels are fitted in parallel and write to their own part of
+    /// the maps. Every thread makes one workspace and one set of trace
+    /// buffers, and reuses them for all of its pixels.
+    pub fn fit_pixels<W, I, F>(
+        &self,
+        sweep_dim: usize,
+        n_params: usize,
+        sigma: Option<&ArrayD<f64>>,
+        init: I,
+        fit_pixel: F,
+    ) -> Result<ImageFit, QufitError>
+    where
+        W: Send,
+        I: Fn() -> W + Sync + Send,
+        F: Fn(&mut W, (usize, usize), &Array1<f64>, &Array1<f64>, Option<&Array1<f64>>) -> PixelFit
+            + Sync,
+    {
+        let traces = pixel_traces(&self.data, sweep_dim)?;
+        let sigma = sigma
+            .map(|sigma| pixel_traces(sigma, sweep_dim))
+            .transpose()?;
+        let x_axis = self.sweep_axis(sweep_dim)?;
+        let (n_points, xdim, ydim) = traces.dim();
+
+        let mut fit = ImageFit::zeros(x_axis.clone(), xdim, ydim, n_params);
+        fit.rows_mut().into_par_iter().enumerate().for_each_init(
+            || {
+                let buffer = Array1::zeros(n_points);
+                (init(), buffer.clone(), sigma.map(|_| buffer))
+            },
+            |(workspace, y, sigma_buffer), (i, mut row)| {
+                for j in 0..ydim {
+                    y.assign(&traces.slice(s![.., i, j]));
+                    if let (Some(buffer), Some(sigma)) = (sigma_buffer.as_mut(), sigma) {
+                        buffer.assign(&sigma.slice(s![.., i, j]));
+                    }
+                    let pixel_fit = fit_pixel(workspace, (i, j), &x_axis, y, sigma_buffer.as_ref());
+                    row.set(j, &pixel_fit);
+                }
+            },
+        );
+        Ok(fit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::DMatrix;
+
+    /// Straight line `p0 + p1 * x`, for which the covariance is known in
+    /// closed form.
+    #[derive(Clone)]
+    struct Line {
+        x: DVector<f64>,
+        y: DVector<f64>,
+        p: DVector<f64>,
+    }
+
+    impl LeastSquaresProblem<f64, Dyn, Dyn> for Line {
+        type ParameterStorage = Owned<f64, Dyn>;
+        type ResidualStorage = Owned<f64, Dyn>;
+        type JacobianStorage = Owned<f64, Dyn, Dyn>;
+
+        fn set_params(&mut self, p: &DVector<f64>) {
+            self.p.copy_from(p)
+        }
+
+        fn params(&self) -> DVector<f64> {
+            self.p.clone()
+        }
+
+        fn residuals(&self) -> Option<DVector<f64>> {
+            Some(&self.y - self.x.map(|x| self.p[0] + self.p[1] * x))
+        }
+
+        fn jacobian(&self) -> Option<DMatrix<f64>> {
+            Some(DMatrix::from_columns(&[
+                self.x.map(|_| -1.0),
+                self.x.map(|x| -x),
+            ]))
+        }
+    }
+
+    #[test]
+    fn test_standard_errors_of_line() {
+        let x = DVector::from_vec(vec![0.0, 1.0, 2.0, 3.0]);
+        let y = Array1::from_vec(vec![0.1, 0.9, 2.1, 2.9]);
+        let result = minimize(
+            Line {
+                x: x.clone(),
+                y: DVector::from_vec(y.to_vec()),
+                p: DVector::from_vec(vec![0.0, 1.0]),
+            },
+            y.view(),
+            Array1::ones(4).view(),
+            false,
+        );
+        // Ordinary least squares: sigma^2 = SSR / (n - 2),
+        // var(slope) = sigma^2 / Sxx, var(intercept) = sigma^2 * sum(x^2) / (n Sxx).
+        let residual_variance: f64 = 0.032 / 2.0;
+        let sxx = 5.0;
+        let slope_error = (residual_variance / sxx).sqrt();
+        let intercept_error = (residual_variance * 14.0 / (4.0 * sxx)).sqrt();
+        assert!((result.params[1] - 0.96).abs() < 1e-9);
+        assert!((result.errors[0] - intercept_error).abs() < 1e-9);
+        assert!((result.errors[1] - slope_error).abs() < 1e-9);
+
+        assert!(result.status.is_success());
+        assert!((result.reduced_chi2 - residual_variance).abs() < 1e-9);
+        assert!((result.rms - (0.032f64 / 4.0).sqrt()).abs() < 1e-9);
+        // Total sum of squares around the mean 1.5 is 4.64.
+        assert!((result.r_squared - (1.0 - 0.032 / 4.64)).abs() < 1e-9);
+
+        // With sigma = 1 taken as absolute, the covariance is not rescaled
+        // by the scatter of the residuals.
+        let absolute = standard_errors(
+            &Line {
+                x,
+                y: DVector::from_vec(y.to_vec()),
+                p: DVector::from_vec(result.params.to_vec()),
+            },
+            true,
+        );
+        assert!((absolute[0] - (14.0 / (4.0 * sxx)).sqrt()).abs() < 1e-9);
+        assert!((absolute[1] - (1.0 / sxx).sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_status_codes_round_trip() {
+        for (code, name) in FitStatus::NAMES.iter().enumerate() {
+            let status = FitStatus::try_from(code as u8).unwrap();
+            assert_eq!(status as usize, code, "{}", name);
+            assert_eq!(status.is_success(), code <= 2, "{}", name);
+        }
+        assert_eq!(FitStatus::try_from(7), Err(7));
+    }
+}
@@ -1,164 +1,320 @@
-use crate::load::DataContainer;
+use crate::error::QufitError;
+use crate::fit_hyperfine_nalgebra::{unit_scale, Hyperfine, HyperfineLorentzian};
+use crate::fit_model::{FitModel, FREE, NON_NEGATIVE};
+use crate::fit_result::{named, ParamUnit};
 use argmm::generic::simple_argmin;
-use levenberg_marquardt::{LeastSquaresProblem, LevenbergMarquardt};
-use nalgebra::{DMatrix, DVector, Dyn, Owned};
-use ndarray::{array, s, Array, Array1, Array3};
-use rayon::prelude::*;
-use std::sync::Mutex;
-
-fn lorentzian(x: f64, params: &DVector<f64>) -> f64 {
-    let a = params[0];
-    let gamma = params[1];
-    let x0 = params[2];
-    1.0 - (a / std::f64::consts::PI * gamma / ((x - x0).powi(2) + gamma.powi(2)))
+use nalgebra::DVector;
+use ndarray::Array1;
+use std::f64::consts::{LN_2, PI};
+use std::str::FromStr;
+
+/// Lineshapes that can be selected in `esr_fit`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EsrModel {
+    Lorentzian,
+    Gaussian,
+    Voigt,
+    Sinc2,
+    Hyperfine(Hyperfine),
 }
 
-#[derive(Clone, Debug)]
-pub struct LorenzianFit {
-    pub x_data: DVector<f64>,
-    pub y_data: DVector<f64>,
-    pub p: DVector<f64>,
+impl EsrModel {
+    /// The fit model of the lineshape. The hyperfine models need the unit
+    /// of the sweep axis (`Hz`, `kHz`, `MHz` or `GHz`) to place the lines,
+    /// the other lineshapes work in any unit.
+    pub fn fit_model(&self, axis_unit: Option<&str>) -> Result<Box<dyn FitModel>, QufitError> {
+        Ok(match self {
+            EsrModel::Lorentzian => Box::new(Lorentzian),
+            EsrModel::Gaussian => Box::new(Gaussian),
+            EsrModel::Voigt => Box::new(PseudoVoigt),
+            EsrModel::Sinc2 => Box::new(Sinc2),
+            EsrModel::Hyperfine(hyperfine) => {
+                let axis_unit = axis_unit.ok_or_else(|| {
+                    QufitError::Value(
+                        "The hyperfine models need the unit of the sweep axis to place the lines, but it is not known. Pass axis_unit (Hz, kHz, MHz or GHz)"
+                            .to_string(),
+                    )
+                })?;
+                let axis_scale = unit_scale(axis_unit).ok_or_else(|| {
+                    QufitError::Value(format!(
+                        "Unknown axis unit '{}'. Use Hz, kHz, MHz or GHz",
+                        axis_unit
+                    ))
+                })?;
+                Box::new(HyperfineLorentzian::new(*hyperfine, axis_scale))
+            }
+        })
+    }
 }
 
-impl LeastSquaresProblem<f64, Dyn, Dyn> for LorenzianFit {
-    type ParameterStorage = Owned<f64, Dyn>;
-    type ResidualStorage = Owned<f64, Dyn>;
-    type JacobianStorage = Owned<f64, Dyn, Dyn>;
+impl FromStr for EsrModel {
+    type Err = String;
 
-    fn set_params(&mut self, p: &DVector<f64>) {
-        self.p.copy_from(p)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "lorentzian" => Ok(EsrModel::Lorentzian),
+            "gaussian" => Ok(EsrModel::Gaussian),
+            "voigt" => Ok(EsrModel::Voigt),
+            "sinc2" => Ok(EsrModel::Sinc2),
+            "n14" | "14n" => Ok(EsrModel::Hyperfine(Hyperfine::N14)),
+            "n15" | "15n" => Ok(EsrModel::Hyperfine(Hyperfine::N15)),
+            _ => Err(format!(
+                "Unknown ESR model '{}'. Available models: lorentzian, gaussian, voigt, sinc2, n14, n15",
+                s
+            )),
+        }
     }
+}
 
-    fn params(&self) -> DVector<f64> {
-        self.p.clone()
+/// Starting point shared by all single dip lineshapes: `[a, width, x0]`.
+pub fn initial_guess(x_data: &Array1<f64>, y_data: &Array1<f64>) -> Vec<f64> {
+    let x_min_guess = x_data[simple_argmin(&y_data.to_vec())];
+    // The default guesses were tuned on a sweep normalised to [0, 1],
+    // scale them to the actual width of the sweep.
+    let span = (x_data[x_data.len() - 1] - x_data[0]).abs();
+    vec![0.02 * span, 0.02 * span, x_min_guess]
+}
+
+/// Lorentzian dip `1 - a / pi * gamma / ((x - x0)^2 + gamma^2)` with area
+/// `a`, half width `gamma` and center `x0`.
+#[derive(Clone, Copy, Debug)]
+pub struct Lorentzian;
+
+impl FitModel for Lorentzian {
+    fn name(&self) -> String {
+        "lorentzian".to_string()
     }
 
-    fn residuals(&self) -> Option<DVector<f64>> {
-        let residuals: DVector<f64> =
-            &self.y_data - self.x_data.map(|x| lorentzian(x, &self.params()));
-        Some(residuals)
+    fn param_info(&self) -> Vec<(String, ParamUnit)> {
+        use ParamUnit::Axis;
+        named(&[("a", Axis), ("gamma", Axis), ("x0", Axis)])
     }
 
-    fn jacobian(&self) -> Option<DMatrix<f64>> {
-        let dl_da = -1.0 * &self.x_data.map(|x| self.gradient_da(x));
-        let dl_dgamma = -1.0 * &self.x_data.map(|x| self.gradient_dgamma(x));
-        let dl_dx0 = -1.0 * &self.x_data.map(|x| self.gradient_dx0(x));
-        let jacobian =
-            DMatrix::from_columns(&[dl_da.to_owned(), dl_dgamma.to_owned(), dl_dx0.to_owned()]);
-        Some(jacobian)
+    fn evaluate(&self, x: f64, p: &DVector<f64>) -> f64 {
+        let a = p[0];
+        let gamma = p[1];
+        let x0 = p[2];
+        1.0 - (a / PI * gamma / ((x - x0).powi(2) + gamma.powi(2)))
     }
-}
 
-impl LorenzianFit {
-    fn gradient_da(&self, x: f64) -> f64 {
-        let params = self.params();
-        let _a = params[0];
-        let gamma = params[1];
-        let x0 = params[2];
-        let pi = std::f64::consts::PI;
+    fn gradient(&self, x: f64, p: &DVector<f64>) -> Vec<f64> {
+        let a = p[0];
+        let gamma = p[1];
+        let x0 = p[2];
         let denom = (x - x0).powi(2) + gamma.powi(2);
 
-        let df_da = 1.0 / pi * gamma / denom;
-        -1.0 * df_da
+        let df_da = 1.0 / PI * gamma / denom;
+        let df_dgamma = a / PI * ((x - x0).powi(2) - gamma.powi(2)) / denom.powi(2);
+        let df_dx0 = 2.0 * a / PI * ((x - x0) * gamma) / denom.powi(2);
+        vec![-df_da, -df_dgamma, -df_dx0]
     }
 
-    fn gradient_dgamma(&self, x: f64) -> f64 {
-        let params = self.params();
-        let a = params[0];
-        let gamma = params[1];
-        let x0 = params[2];
-        let pi = std::f64::consts::PI;
-        let denom = (x - x0).powi(2) + gamma.powi(2);
+    fn bounds(&self) -> Vec<(f64, f64)> {
+        vec![FREE, NON_NEGATIVE, FREE]
+    }
 
-        let df_dgamma = a / pi * ((x - x0).powi(2) - gamma.powi(2)) / denom.powi(2);
-        -1.0 * df_dgamma
+    fn initial_guess(&self, x_data: &Array1<f64>, y_data: &Array1<f64>) -> Vec<f64> {
+        initial_guess(x_data, y_data)
     }
+}
 
-    fn gradient_dx0(&self, x: f64) -> f64 {
-        let params = self.params();
-        let a = params[0];
-        let gamma = params[1];
-        let x0 = params[2];
-        let pi = std::f64::consts::PI;
-        let denom = (x - x0).powi(2) + gamma.powi(2);
+/// Gaussian dip with area `a`, standard deviation `sigma` and center `x0`.
+#[derive(Clone, Copy, Debug)]
+pub struct Gaussian;
+
+impl FitModel for Gaussian {
+    fn name(&self) -> String {
+        "gaussian".to_string()
+    }
+
+    fn param_info(&self) -> Vec<(String, ParamUnit)> {
+        use ParamUnit::Axis;
+        named(&[("a", Axis), ("sigma", Axis), ("x0", Axis)])
+    }
 
-        let df_dx0 = 2.0 * a / pi * ((x - x0) * gamma) / denom.powi(2);
-        -1.0 * df_dx0
+    fn evaluate(&self, x: f64, p: &DVector<f64>) -> f64 {
+        let a = p[0];
+        let sigma = p[1];
+        let x0 = p[2];
+        let norm = 1.0 / (sigma * (2.0 * PI).sqrt());
+        1.0 - a * norm * (-(x - x0).powi(2) / (2.0 * sigma.powi(2))).exp()
+    }
+
+    fn gradient(&self, x: f64, p: &DVector<f64>) -> Vec<f64> {
+        let sigma = p[1];
+        let x0 = p[2];
+        // Value of the dip for unit area, i.e. the normalised Gaussian.
+        let shape = 1.0 - self.evaluate(x, &DVector::from_vec(vec![1.0, sigma, x0]));
+        let dip = p[0] * shape;
+
+        let df_da = shape;
+        let df_dsigma = dip * ((x - x0).powi(2) / sigma.powi(3) - 1.0 / sigma);
+        let df_dx0 = dip * (x - x0) / sigma.powi(2);
+        vec![-df_da, -df_dsigma, -df_dx0]
+    }
+
+    fn bounds(&self) -> Vec<(f64, f64)> {
+        vec![FREE, NON_NEGATIVE, FREE]
+    }
+
+    fn initial_guess(&self, x_data: &Array1<f64>, y_data: &Array1<f64>) -> Vec<f64> {
+        initial_guess(x_data, y_data)
     }
 }
 
-fn fit(x_data: Array1<f64>, y_data: Array1<f64>) -> Option<Array1<f64>> {
-    let x_min_guess = simple_argmin(&y_data.to_vec()) as f64 / y_data.len() as f64;
-    let init_param = vec![0.02, 0.02, x_min_guess];
-    let x = DVector::from_vec(x_data.to_vec());
-    let data = DVector::from_vec(y_data.to_vec());
-
-    let problem = LorenzianFit {
-        x_data: x,
-        y_data: data,
-        p: DVector::from_vec(init_param),
-    };
-
-    let (result, report) = LevenbergMarquardt::new().minimize(problem);
-    let opt_params = result.p;
-    let opt_params: Array1<f64> =
-        Array1::from_shape_vec(opt_params.nrows(), opt_params.data.into()).unwrap();
-    if report.termination.was_successful() {
-        return Some(opt_params);
-    } else {
-        return None;
+/// Pseudo-Voigt dip: a mix `eta * L + (1 - eta) * G` of a Lorentzian and a
+/// Gaussian with the same area `a`, half width at half maximum `w` and
+/// center `x0`.
+#[derive(Clone, Copy, Debug)]
+pub struct PseudoVoigt;
+
+impl PseudoVoigt {
+    /// Parameters of the Lorentzian and the Gaussian component.
+    fn components(p: &DVector<f64>) -> (DVector<f64>, DVector<f64>) {
+        let sigma = p[1] / (2.0 * LN_2).sqrt();
+        (
+            DVector::from_vec(vec![p[0], p[1], p[2]]),
+            DVector::from_vec(vec![p[0], sigma, p[2]]),
+        )
     }
 }
 
-impl DataContainer {
-    pub fn fit_esr_image(&self) -> Array3<f64> {
-        let zdim = self.data.shape()[1];
-        let x_axis = Array::linspace(0.0, 1.0, zdim);
-        let dims: Vec<usize> = self.data.shape().iter().cloned().skip(3).collect();
-        match dims.len() {
-            2 => {
-                let xdim = dims[0];
-                let ydim = dims[1];
-                let re: Array3<f64> = Array3::zeros((dims[0], dims[1], 3));
-                let re_mutex = Mutex::new(re);
-                (0..xdim).into_par_iter().for_each(|i| {
-                    for j in 0..ydim {
-                        let res = fit(
-                            x_axis.clone(),
-                            self.data.slice(s![0, .., 0, i, j]).to_owned(),
-                        );
-                        let mut re = re_mutex.lock().unwrap();
-                        match res {
-                            Some(result) => re.slice_mut(s![i, j, ..]).assign(&result),
-                            None => {
-                                re.slice_mut(s![i, j, ..]).assign(&array![0.0, 0.0, 0.0]);
-                                println!("The optmization failed! Assigning default zero values!");
-                            }
-                        }
-                    }
-                });
-                let res = re_mutex.into_inner().unwrap();
-                res
-            }
-            1 => {
-                let xdim = dims[0];
-                let re: Array3<f64> = Array3::zeros((dims[0], 1, 3));
-                let re_mutex = Mutex::new(re);
-                (0..xdim).into_par_iter().for_each(|i| {
-                    let res = fit(x_axis.clone(), self.data.slice(s![0, .., 0, i]).to_owned());
-                    let mut re = re_mutex.lock().unwrap();
-                    match res {
-                        Some(result) => re.slice_mut(s![i, 0, ..]).assign(&result),
-                        None => {
-                            re.slice_mut(s![i, 0, ..]).assign(&array![0.0, 0.0, 0.0]);
-                            println!("The optmization failed! Assigning default zero values!");
-                        }
-                    }
-                });
-                let res = re_mutex.into_inner().unwrap();
-                res
-            }
-            _ => panic!("For the size of the input array there are no known fitting methos"),
-        }
+impl FitModel for PseudoVoigt {
+    fn name(&self) -> String {
+        "voigt".to_string()
+    }
+
+    fn param_info(&self) -> Vec<(String, ParamUnit)> {
+        use ParamUnit::{Axis, None};
+        named(&[("a", Axis), ("w", Axis), ("x0", Axis), ("eta", None)])
+    }
+
+    fn evaluate(&self, x: f64, p: &DVector<f64>) -> f64 {
+        let eta = p[3];
+        let (lorentz, gauss) = Self::components(p);
+        let l = 1.0 - Lorentzian.evaluate(x, &lorentz);
+        let g = 1.0 - Gaussian.evaluate(x, &gauss);
+        1.0 - (eta * l + (1.0 - eta) * g)
+    }
+
+    fn gradient(&self, x: f64, p: &DVector<f64>) -> Vec<f64> {
+        let eta = p[3];
+        let sigma_per_w = 1.0 / (2.0 * LN_2).sqrt();
+        let (lorentz, gauss) = Self::components(p);
+        let dl = Lorentzian.gradient(x, &lorentz);
+        let dg = Gaussian.gradient(x, &gauss);
+        // The Gaussian width scales with w, hence the chain rule factor.
+        vec![
+            eta * dl[0] + (1.0 - eta) * dg[0],
+            eta * dl[1] + (1.0 - eta) * dg[1] * sigma_per_w,
+            eta * dl[2] + (1.0 - eta) * dg[2],
+            Lorentzian.evaluate(x, &lorentz) - Gaussian.evaluate(x, &gauss),
+        ]
+    }
+
+    fn bounds(&self) -> Vec<(f64, f64)> {
+        vec![FREE, NON_NEGATIVE, FREE, (0.0, 1.0)]
+    }
+
+    fn initial_guess(&self, x_data: &Array1<f64>, y_data: &Array1<f64>) -> Vec<f64> {
+        let mut init_param = initial_guess(x_data, y_data);
+        init_param.push(0.5);
+        init_param
+    }
+}
+
+/// Pulsed ODMR lineshape of a pi-pulse with Rabi frequency `omega`
+/// (in units of the sweep axis), contrast `c` and center `x0`.
+#[derive(Clone, Copy, Debug)]
+pub struct Sinc2;
+
+impl Sinc2 {
+    /// Transition probability P = omega^2 / R^2 * sin^2(pi / 2 * R / omega)
+    /// with R^2 = omega^2 + delta^2.
+    fn probability(omega: f64, delta: f64) -> f64 {
+        let r2 = omega.powi(2) + delta.powi(2);
+        let phi = PI / 2.0 * r2.sqrt() / omega;
+        omega.powi(2) / r2 * phi.sin().powi(2)
+    }
+
+    /// Returns `(dP/domega, dP/ddelta)`.
+    fn probability_gradients(omega: f64, delta: f64) -> (f64, f64) {
+        let r2 = omega.powi(2) + delta.powi(2);
+        let r = r2.sqrt();
+        let u = omega.powi(2) / r2;
+        let phi = PI / 2.0 * r / omega;
+        let s = phi.sin().powi(2);
+        let ds_dphi = (2.0 * phi).sin();
+
+        let du_domega = 2.0 * omega * delta.powi(2) / r2.powi(2);
+        let du_ddelta = -2.0 * omega.powi(2) * delta / r2.powi(2);
+        let dphi_domega = -PI * delta.powi(2) / (2.0 * r * omega.powi(2));
+        let dphi_ddelta = PI / (2.0 * omega) * delta / r;
+
+        (
+            du_domega * s + u * ds_dphi * dphi_domega,
+            du_ddelta * s + u * ds_dphi * dphi_ddelta,
+        )
+    }
+}
+
+impl FitModel for Sinc2 {
+    fn name(&self) -> String {
+        "sinc2".to_string()
+    }
+
+    fn param_info(&self) -> Vec<(String, ParamUnit)> {
+        use ParamUnit::{Axis, None};
+        named(&[("c", None), ("omega", Axis), ("x0", Axis)])
+    }
+
+    fn evaluate(&self, x: f64, p: &DVector<f64>) -> f64 {
+        1.0 - p[0] * Self::probability(p[1], x - p[2])
+    }
+
+    fn gradient(&self, x: f64, p: &DVector<f64>) -> Vec<f64> {
+        let c = p[0];
+        let delta = x - p[2];
+        let (dp_domega, dp_ddelta) = Self::probability_gradients(p[1], delta);
+        // delta = x - x0
+        vec![
+            -Self::probability(p[1], delta),
+            -c * dp_domega,
+            c * dp_ddelta,
+        ]
+    }
+
+    fn bounds(&self) -> Vec<(f64, f64)> {
+        vec![FREE, NON_NEGATIVE, FREE]
+    }
+
+    fn initial_guess(&self, x_data: &Array1<f64>, y_data: &Array1<f64>) -> Vec<f64> {
+        let mut init_param = initial_guess(x_data, y_data);
+        // The contrast of the pulsed lineshape is the depth of the dip itself.
+        init_param[0] = 1.0 - y_data.fold(f64::INFINITY, |acc, &y| acc.min(y));
+        init_param
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fit_model::{assert_fit_recovers, assert_gradient_matches};
+
+    #[test]
+    fn test_lineshape_gradients() {
+        let x = Array1::linspace(0.0, 1.0, 101);
+        assert_gradient_matches(&Lorentzian, &x, &[0.01, 0.05, 0.4]);
+        assert_gradient_matches(&Gaussian, &x, &[0.01, 0.05, 0.4]);
+        assert_gradient_matches(&PseudoVoigt, &x, &[0.01, 0.05, 0.4, 0.3]);
+        assert_gradient_matches(&Sinc2, &x, &[0.1, 0.05, 0.4]);
+    }
+
+    #[test]
+    fn test_lineshape_fits_recover_parameters() {
+        let x = Array1::linspace(0.0, 1.0, 201);
+        assert_fit_recovers(&Gaussian, &x, &[0.01, 0.04, 0.45]);
+        assert_fit_recovers(&PseudoVoigt, &x, &[0.01, 0.03, 0.45, 0.4]);
+        assert_fit_recovers(&Sinc2, &x, &[0.08, 0.05, 0.45]);
     }
 }
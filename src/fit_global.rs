@@ -0,0 +1,513 @@
+use crate::error::QufitError;
+use crate::fit_common::{pixel_traces, FitStatus, ImageFit, PixelFit};
+use crate::fit_model::{fit_trace, Constraints, FitModel, FitSettings, ModelProblem};
+use crate::fit_result::FitResult;
+use crate::load::DataContainer;
+use levenberg_marquardt::LeastSquaresProblem;
+use nalgebra::{DMatrix, DVector};
+use ndarray::{s, Array1, ArrayD, ArrayView1};
+use rayon::prelude::*;
+
+const MAX_ITERATIONS: usize = 200;
+const FTOL: f64 = 1e-12;
+const XTOL: f64 = 1e-12;
+
+/// Position of every free parameter in the internal variables of a pixel,
+/// split into the parameters shared by all pixels and the local ones.
+struct Layout {
+    shared: Vec<usize>,
+    local: Vec<usize>,
+}
+
+impl Layout {
+    fn new(constraints: &Constraints, shared: &[usize]) -> Self {
+        let (shared, local): (Vec<_>, Vec<_>) = constraints
+            .free()
+            .enumerate()
+            .partition(|(_, k)| shared.contains(k));
+        Layout {
+            shared: shared.into_iter().map(|(position, _)| position).collect(),
+            local: local.into_iter().map(|(position, _)| position).collect(),
+        }
+    }
+
+    /// Internal variables of one pixel.
+    fn assemble(&self, shared: &DVector<f64>, local: &DVector<f64>) -> DVector<f64> {
+        let mut u = DVector::zeros(self.shared.len() + self.local.len());
+        for (a, &position) in self.shared.iter().enumerate() {
+            u[position] = shared[a];
+        }
+        for (b, &position) in self.local.iter().enumerate() {
+            u[position] = local[b];
+        }
+        u
+    }
+
+    /// Splits the internal variables of one pixel into shared and local.
+    fn split(&self, u: &DVector<f64>) -> (DVector<f64>, DVector<f64>) {
+        (u.select_rows(&self.shared), u.select_rows(&self.local))
+    }
+}
+
+/// Contribution of one pixel to the normal equations `J^T J delta = -J^T r`,
+/// with the Jacobian split into shared (`s`) and local (`l`) columns. The
+/// local columns of different pixels do not overlap, so `J^T J` is block
+/// diagonal apart from the shared rows and columns.
+struct Block {
+    jss: DMatrix<f64>,
+    jsl: DMatrix<f64>,
+    jll: DMatrix<f64>,
+    gs: DVector<f64>,
+    gl: DVector<f64>,
+}
+
+/// Measured trace of one pixel and the weight of every point.
+struct Trace {
+    y: DVector<f64>,
+    weights: DVector<f64>,
+}
+
+/// Least squares problem of fitting `model` to all `traces` at once, with
+/// the parameters in `layout.shared` common to all of them.
+struct GlobalProblem<'a, M: FitModel + ?Sized> {
+    model: &'a M,
+    constraints: &'a Constraints,
+    layout: &'a Layout,
+    x: &'a DVector<f64>,
+    traces: &'a [Trace],
+}
+
+impl<M: FitModel + ?Sized> GlobalProblem<'_, M> {
+    fn pixel<'b>(
+        &'b self,
+        trace: &'b Trace,
+        shared: &DVector<f64>,
+        local: &DVector<f64>,
+    ) -> ModelProblem<'b, M> {
+        ModelProblem {
+            model: self.model,
+            constraints: self.constraints,
+            x_data: self.x,
+            y_data: &trace.y,
+            weights: &trace.weights,
+            p: self.layout.assemble(shared, local),
+        }
+    }
+
+    /// Sum of the squared residuals of every pixel.
+    fn pixel_costs(&self, shared: &DVector<f64>, locals: &[DVector<f64>]) -> Vec<f64> {
+        self.traces
+            .par_iter()
+            .zip(locals)
+            .map(|(trace, local)| {
+                self.pixel(trace, shared, local)
+                    .residuals()
+                    .map_or(f64::NAN, |r| r.norm_squared())
+            })
+            .collect()
+    }
+
+    fn cost(&self, shared: &DVector<f64>, locals: &[DVector<f64>]) -> f64 {
+        self.pixel_costs(shared, locals).iter().sum()
+    }
+
+    fn blocks(&self, shared: &DVector<f64>, locals: &[DVector<f64>]) -> Option<Vec<Block>> {
+        self.traces
+            .par_iter()
+            .zip(locals)
+            .map(|(trace, local)| {
+                let problem = self.pixel(trace, shared, local);
+                let (r, j) = (problem.residuals()?, problem.jacobian()?);
+                let js = j.select_columns(&self.layout.shared);
+                let jl = j.select_columns(&self.layout.local);
+                Some(Block {
+                    jss: js.transpose() * &js,
+                    jsl: js.transpose() * &jl,
+                    jll: jl.transpose() * &jl,
+                    gs: -(js.transpose() * &r),
+                    gl: -(jl.transpose() * &r),
+                })
+            })
+            .collect()
+    }
+
+    /// Levenberg-Marquardt step for the damping `lambda`. The local
+    /// parameters are eliminated with the Schur complement, which leaves a
+    /// system of the size of the shared parameters.
+    fn step(&self, blocks: &[Block], lambda: f64) -> Option<(DVector<f64>, Vec<DVector<f64>>)> {
+        let k = self.layout.shared.len();
+        let inverses: Vec<DMatrix<f64>> = blocks
+            .par_iter()
+            .map(|block| damped(&block.jll, lambda).try_inverse())
+            .collect::<Option<_>>()?;
+        let jss = blocks
+            .iter()
+            .fold(DMatrix::zeros(k, k), |sum, block| sum + &block.jss);
+        let (reduction, rhs_reduction) = blocks
+            .par_iter()
+            .zip(&inverses)
+            .map(|(block, inverse)| {
+                let jsl_inv = &block.jsl * inverse;
+                (&jsl_inv * block.jsl.transpose(), &jsl_inv * &block.gl)
+            })
+            .reduce(
+                || (DMatrix::zeros(k, k), DVector::zeros(k)),
+                |a, b| (a.0 + b.0, a.1 + b.1),
+            );
+        let gs = blocks
+            .iter()
+            .fold(DVector::zeros(k), |sum, block| sum + &block.gs);
+        let schur = damped(&jss, lambda) - reduction;
+        let delta_shared = schur.cholesky()?.solve(&(gs - rhs_reduction));
+        let delta_locals = blocks
+            .par_iter()
+            .zip(&inverses)
+            .map(|(block, inverse)| inverse * (&block.gl - block.jsl.transpose() * &delta_shared))
+            .collect();
+        Some((delta_shared, delta_locals))
+    }
+
+    /// Minimizes the total cost from the internal variables `shared` and
+    /// `locals`. Returns the number of cost evaluations and why it stopped.
+    fn solve(&self, shared: &mut DVector<f64>, locals: &mut [DVector<f64>]) -> (FitStatus, usize) {
+        let mut cost = self.cost(shared, locals);
+        let mut evaluations = 1;
+        if !cost.is_finite() {
+            return (FitStatus::Numerical, evaluations);
+        }
+        let mut lambda = 1e-3;
+        for _ in 0..MAX_ITERATIONS {
+            if cost == 0.0 {
+                return (FitStatus::ResidualsZero, evaluations);
+            }
+            let Some(blocks) = self.blocks(shared, locals) else {
+                return (FitStatus::Numerical, evaluations);
+            };
+            loop {
+                if lambda > 1e16 {
+                    return (FitStatus::NoImprovementPossible, evaluations);
+                }
+                let Some((delta_shared, delta_locals)) = self.step(&blocks, lambda) else {
+                    lambda *= 10.0;
+                    continue;
+                };
+                let trial_shared = &*shared + &delta_shared;
+                let trial_locals: Vec<DVector<f64>> = locals
+                    .iter()
+                    .zip(&delta_locals)
+                    .map(|(local, delta)| local + delta)
+                    .collect();
+                let trial_cost = self.cost(&trial_shared, &trial_locals);
+                evaluations += 1;
+                if trial_cost.is_nan() || trial_cost > cost {
+                    lambda *= 10.0;
+                    continue;
+                }
+
+                let step_norm = (delta_shared.norm_squared()
+                    + delta_locals.iter().map(|d| d.norm_squared()).sum::<f64>())
+                .sqrt();
+                let param_norm = (trial_shared.norm_squared()
+                    + trial_locals.iter().map(|l| l.norm_squared()).sum::<f64>())
+                .sqrt();
+                let converged =
+                    cost - trial_cost <= FTOL * cost || step_norm <= XTOL * (param_norm + XTOL);
+                *shared = trial_shared;
+                locals.clone_from_slice(&trial_locals);
+                cost = trial_cost;
+                lambda = (lambda / 10.0).max(1e-12);
+                if converged {
+                    return (FitStatus::Converged, evaluations);
+                }
+                break;
+            }
+        }
+        (FitStatus::LostPatience, evaluations)
+    }
+
+    /// Standard errors of the shared and of the local parameters of every
+    /// pixel from the covariance `(J^T J)^-1`, multiplied by `scale`.
+    fn errors(
+        &self,
+        shared: &DVector<f64>,
+        locals: &[DVector<f64>],
+        scale: f64,
+    ) -> (DVector<f64>, Vec<DVector<f64>>) {
+        let k = self.layout.shared.len();
+        let m = self.layout.local.len();
+        let nan = || {
+            (
+                DVector::from_element(k, f64::NAN),
+                vec![DVector::from_element(m, f64::NAN); locals.len()],
+            )
+        };
+        let Some(blocks) = self.blocks(shared, locals) else {
+            return nan();
+        };
+        let Some(inverses) = blocks
+            .iter()
+            .map(|block| block.jll.clone().try_inverse())
+            .collect::<Option<Vec<_>>>()
+        else {
+            return nan();
+        };
+        let schur = blocks
+            .iter()
+            .zip(&inverses)
+            .fold(DMatrix::zeros(k, k), |sum, (block, inverse)| {
+                sum + &block.jss - &block.jsl * inverse * block.jsl.transpose()
+            });
+        let Some(shared_covariance) = schur.try_inverse() else {
+            return nan();
+        };
+        let to_errors =
+            |covariance: &DMatrix<f64>| covariance.diagonal().map(|v| (v * scale).sqrt());
+        let local_errors = blocks
+            .par_iter()
+            .zip(&inverses)
+            .map(|(block, inverse)| {
+                let coupling = &block.jsl * inverse;
+                to_errors(&(inverse + coupling.transpose() * &shared_covariance * coupling))
+            })
+            .collect();
+        (to_errors(&shared_covariance), local_errors)
+    }
+}
+
+/// `matrix` with its diagonal scaled by `1 + lambda`.
+fn damped(matrix: &DMatrix<f64>, lambda: f64) -> DMatrix<f64> {
+    let mut damped = matrix.clone();
+    for i in 0..matrix.nrows() {
+        damped[(i, i)] += lambda * matrix[(i, i)].max(1e-12);
+    }
+    damped
+}
+
+fn median(mut values: Vec<f64>) -> f64 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    let n = values.len();
+    if n % 2 == 1 {
+        values[n / 2]
+    } else {
+        (values[n / 2 - 1] + values[n / 2]) / 2.0
+    }
+}
+
+impl DataContainer {
+    /// Fits `model` to all pixels at once, with the parameters in `shared`
+    /// (indices into the model parameters) common to the whole image.
+    ///
+    /// Every pixel is first fitted on its own with `settings`. The pixels
+    /// that converged start the global fit from the median of their shared
+    /// parameters; the other pixels are fitted again afterwards with the
+    /// shared parameters fixed at the global result. The diagnostics of the
+    /// global pixels report the global termination, and their reduced
+    /// chi-square counts only the local parameters. Fails if no pixel
+    /// converged on its own.
+    pub fn fit_global_image<M: FitModel + ?Sized>(
+        &self,
+        model: &M,
+        settings: &FitSettings,
+        shared: &[usize],
+        sweep_dim: usize,
+        sigma: Option<&ArrayD<f64>>,
+        axis_unit: Option<&str>,
+    ) -> Result<FitResult, QufitError> {
+        let independent = self.fit_model_image(model, settings, sweep_dim, sigma, axis_unit)?;
+        let x_axis = independent.fit.x_axis.clone();
+        let traces = pixel_traces(&self.data, sweep_dim)?;
+        let sigma = sigma
+            .map(|sigma| pixel_traces(sigma, sweep_dim))
+            .transpose()?;
+        let (_, xdim, ydim) = traces.dim();
+        let trace = |(i, j): (usize, usize)| {
+            let y = traces.slice(s![.., i, j]).to_owned();
+            let sigma = sigma.map(|sigma| sigma.slice(s![.., i, j]).to_owned());
+            (y, sigma)
+        };
+
+        let success = independent.fit.success_mask();
+        let (global, rest): (Vec<_>, Vec<_>) = (0..xdim)
+            .flat_map(|i| (0..ydim).map(move |j| (i, j)))
+            .partition(|&pixel| {
+                let (y, sigma) = trace(pixel);
+                success[pixel]
+                    && y.iter().all(|y| y.is_finite())
+                    && sigma.is_none_or(|sigma| sigma.iter().all(|s| s.is_finite()))
+            });
+        if global.is_empty() {
+            return Err(QufitError::Fit(
+                "No pixel converged on its own, so the shared parameters have no starting point"
+                    .to_string(),
+            ));
+        }
+
+        let constraints = &settings.constraints;
+        let layout = Layout::new(constraints, shared);
+        let mut start = independent.fit.params.clone();
+        for &k in shared {
+            let value = median(global.iter().map(|&(i, j)| start[[i, j, k]]).collect());
+            start.slice_mut(s![.., .., k]).fill(value);
+        }
+        let x = DVector::from_vec(x_axis.to_vec());
+        let global_traces: Vec<Trace> = global
+            .iter()
+            .map(|&pixel| {
+                let (y, sigma) = trace(pixel);
+                let weights = match sigma {
+                    Some(sigma) => sigma.mapv(|s| 1.0 / s),
+                    None => Array1::ones(y.len()),
+                };
+                Trace {
+                    y: DVector::from_vec(y.to_vec()),
+                    weights: DVector::from_vec(weights.to_vec()),
+                }
+            })
+            .collect();
+        let problem = GlobalProblem {
+            model,
+            constraints,
+            layout: &layout,
+            x: &x,
+            traces: &global_traces,
+        };
+        let internal = |(i, j): (usize, usize)| {
+            let p = start.slice(s![i, j, ..]).to_vec();
+            layout.split(&constraints.to_internal(&p))
+        };
+        let mut shared_u = internal(global[0]).0;
+        let mut locals: Vec<DVector<f64>> = global.iter().map(|&pixel| internal(pixel).1).collect();
+        let (status, evaluations) = problem.solve(&mut shared_u, &mut locals);
+
+        // The errors are evaluated for the model parameters directly, as in
+        // `fit_trace`, because the internal covariance is singular at a bound.
+        let params: Vec<DVector<f64>> = locals
+            .iter()
+            .map(|local| constraints.to_external(&layout.assemble(&shared_u, local)))
+            .collect();
+        let unbounded = constraints.without_bounds();
+        let unbounded_problem = GlobalProblem {
+            constraints: &unbounded,
+            ..problem
+        };
+        let (unbounded_shared, unbounded_locals): (Vec<_>, Vec<_>) = params
+            .iter()
+            .map(|p| layout.split(&unbounded.to_internal(p.as_slice())))
+            .unzip();
+        let pixel_costs = problem.pixel_costs(&shared_u, &locals);
+        let n_points: usize = global_traces.iter().map(|trace| trace.y.len()).sum();
+        let dof =
+            n_points as f64 - (layout.shared.len() + layout.local.len() * global.len()) as f64;
+        let reduced_chi2 = pixel_costs.iter().sum::<f64>() / dof;
+        // Known uncertainties give the covariance directly, see
+        // `standard_errors`.
+        let scale = match sigma {
+            Some(_) => 1.0,
+            None if dof > 0.0 => reduced_chi2,
+            None => f64::NAN,
+        };
+        let (shared_errors, local_errors) =
+            unbounded_problem.errors(&unbounded_shared[0], &unbounded_locals, scale);
+
+        let mut fit = ImageFit::zeros(x_axis.clone(), xdim, ydim, model.n_params());
+        for (n, &pixel) in global.iter().enumerate() {
+            let free_errors = layout.assemble(&shared_errors, &local_errors[n]);
+            let trace = &global_traces[n];
+            let mut pixel_fit = PixelFit::new(
+                Array1::from_vec(params[n].as_slice().to_vec()),
+                constraints.all_errors(free_errors.as_slice()),
+                status,
+                evaluations,
+                pixel_costs[n],
+                ArrayView1::from(trace.y.as_slice()),
+                ArrayView1::from(trace.weights.as_slice()),
+            );
+            let local_dof = trace.y.len() as f64 - layout.local.len() as f64;
+            pixel_fit.reduced_chi2 = if local_dof > 0.0 {
+                pixel_costs[n] / local_dof
+            } else {
+                f64::NAN
+            };
+            fit.set(pixel, &pixel_fit);
+        }
+
+        let mut fixed_shared = constraints.clone();
+        for &k in shared {
+            fixed_shared.fixed[k] = Some(params[0][k]);
+        }
+        let refits: Vec<PixelFit> = rest
+            .par_iter()
+            .map(|&pixel| {
+                let (y, sigma) = trace(pixel);
+                fit_trace(
+                    model,
+                    &fixed_shared,
+                    &settings.loss,
+                    &x_axis,
+                    &y,
+                    sigma.as_ref(),
+                )
+            })
+            .collect();
+        for (&pixel, pixel_fit) in rest.iter().zip(&refits) {
+            fit.set(pixel, pixel_fit);
+        }
+        Ok(FitResult::new(
+            &model.name(),
+            model.param_info(),
+            axis_unit,
+            fit,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fit_esr_nalgebra::Lorentzian;
+    use crate::fit_loss::RobustLoss;
+    use ndarray::Array5;
+
+    #[test]
+    fn test_shared_width_is_common_to_all_pixels() {
+        let n = 81;
+        let x = Array1::linspace(0.0, 1.0, n);
+        let mut data = Array5::zeros((1, n, 1, 2, 3));
+        for i in 0..2 {
+            for j in 0..3 {
+                let truth =
+                    DVector::from_vec(vec![0.008 + 0.001 * i as f64, 0.05, 0.35 + 0.05 * j as f64]);
+                let trace = x.mapv(|x| Lorentzian.evaluate(x, &truth));
+                data.slice_mut(s![0, .., 0, i, j]).assign(&trace);
+                // Deterministic noise, different for every pixel.
+                for (t, y) in data.slice_mut(s![0, .., 0, i, j]).iter_mut().enumerate() {
+                    *y += 2e-4 * ((t * (3 * i + j + 2)) as f64 * 1.7).sin();
+                }
+            }
+        }
+        let container = DataContainer::from_data(data.into_dyn());
+        let settings = FitSettings {
+            constraints: Constraints::new(&Lorentzian),
+            loss: RobustLoss::default(),
+            coarse_bin: None,
+        };
+
+        let independent = container
+            .fit_model_image(&Lorentzian, &settings, 1, None, None)
+            .unwrap();
+        let global = container
+            .fit_global_image(&Lorentzian, &settings, &[1], 1, None, None)
+            .unwrap();
+        assert!(global.fit.success_mask().iter().all(|&ok| ok));
+        let gamma = global.fit.params[[0, 0, 1]];
+        assert!((gamma - 0.05).abs() < 1e-3);
+        for i in 0..2 {
+            for j in 0..3 {
+                assert_eq!(global.fit.params[[i, j, 1]], gamma);
+                assert!((global.fit.params[[i, j, 2]] - (0.35 + 0.05 * j as f64)).abs() < 1e-3);
+                // The shared width is determined by all pixels together.
+                assert!(global.fit.errors[[i, j, 1]] < independent.fit.errors[[i, j, 1]]);
+            }
+        }
+    }
+}
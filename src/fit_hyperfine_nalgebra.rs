@@ -0,0 +1,182 @@
+use crate::fit_esr_nalgebra::Lorentzian;
+use crate::fit_model::FitModel;
+use crate::fit_result::ParamUnit;
+use nalgebra::DVector;
+use ndarray::Array1;
+use std::f64::consts::PI;
+
+/// Hyperfine coupling of the NV electron spin to its own nitrogen nucleus.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hyperfine {
+    /// 14N (I = 1): triplet with 2.16 MHz spacing.
+    N14,
+    /// 15N (I = 1/2): doublet with 3.03 MHz spacing.
+    N15,
+}
+
+impl Hyperfine {
+    /// Line spacing in Hz.
+    pub fn splitting(&self) -> f64 {
+        match self {
+            Hyperfine::N14 => 2.16e6,
+            Hyperfine::N15 => 3.03e6,
+        }
+    }
+
+    /// Positions of the lines relative to the center for a given spacing.
+    pub fn offsets(&self, splitting: f64) -> Vec<f64> {
+        match self {
+            Hyperfine::N14 => vec![-splitting, 0.0, splitting],
+            Hyperfine::N15 => vec![-splitting / 2.0, splitting / 2.0],
+        }
+    }
+}
+
+/// Number of Hz in one unit of the sweep axis.
+pub fn unit_scale(unit: &str) -> Option<f64> {
+    match unit {
+        "Hz" => Some(1.0),
+        "kHz" => Some(1e3),
+        "MHz" => Some(1e6),
+        "GHz" => Some(1e9),
+        _ => None,
+    }
+}
+
+/// Hyperfine multiplet of Lorentzian dips with a common contrast `a`,
+/// linewidth `gamma` and center `x0`. The line spacing is fixed and given
+/// in units of the sweep axis.
+#[derive(Clone, Debug)]
+pub struct HyperfineLorentzian {
+    pub hyperfine: Hyperfine,
+    pub offsets: Vec<f64>,
+}
+
+impl HyperfineLorentzian {
+    /// `axis_scale` is the number of Hz per unit of the sweep axis, e.g.
+    /// `1e6` for a sweep in MHz.
+    pub fn new(hyperfine: Hyperfine, axis_scale: f64) -> Self {
+        HyperfineLorentzian {
+            hyperfine,
+            offsets: hyperfine.offsets(hyperfine.splitting() / axis_scale),
+        }
+    }
+
+    /// Picks the center for which the multiplet pattern overlaps best with
+    /// the dips in the data.
+    fn center_guess(&self, x_data: &Array1<f64>, y_data: &Array1<f64>) -> f64 {
+        let n = x_data.len();
+        let step = (x_data[n - 1] - x_data[0]) / (n - 1) as f64;
+        let nearest = |x: f64| ((x - x_data[0]) / step).round().clamp(0.0, (n - 1) as f64) as usize;
+        x_data
+            .iter()
+            .map(|&center| {
+                let score: f64 = self
+                    .offsets
+                    .iter()
+                    .map(|o| y_data[nearest(center + o)])
+                    .sum();
+                (center, score)
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map_or(x_data[0], |(center, _)| center)
+    }
+}
+
+impl FitModel for HyperfineLorentzian {
+    fn name(&self) -> String {
+        match self.hyperfine {
+            Hyperfine::N14 => "n14".to_string(),
+            Hyperfine::N15 => "n15".to_string(),
+        }
+    }
+
+    fn param_info(&self) -> Vec<(String, ParamUnit)> {
+        Lorentzian.param_info()
+    }
+
+    fn evaluate(&self, x: f64, p: &DVector<f64>) -> f64 {
+        // Every line is a Lorentzian dip 1 - L(x - offset).
+        let dips: f64 = self
+            .offsets
+            .iter()
+            .map(|offset| 1.0 - Lorentzian.evaluate(x - offset, p))
+            .sum();
+        1.0 - dips
+    }
+
+    fn gradient(&self, x: f64, p: &DVector<f64>) -> Vec<f64> {
+        let mut gradient = vec![0.0; 3];
+        for offset in &self.offsets {
+            for (total, line) in gradient.iter_mut().zip(Lorentzian.gradient(x - offset, p)) {
+                *total += line;
+            }
+        }
+        gradient
+    }
+
+    fn bounds(&self) -> Vec<(f64, f64)> {
+        Lorentzian.bounds()
+    }
+
+    fn initial_guess(&self, x_data: &Array1<f64>, y_data: &Array1<f64>) -> Vec<f64> {
+        let x0_guess = self.center_guess(x_data, y_data);
+        let spacing = self.offsets[self.offsets.len() - 1] - self.offsets[0];
+        let gamma_guess = spacing / (2.0 * self.offsets.len() as f64);
+        let depth = 1.0 - y_data.fold(f64::INFINITY, |acc, &y| acc.min(y));
+        vec![depth.max(0.0) * PI * gamma_guess, gamma_guess, x0_guess]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::QufitError;
+    use crate::fit_esr_nalgebra::EsrModel;
+    use crate::fit_model::{assert_fit_recovers, assert_gradient_matches};
+    use crate::load::{DataContainer, FitOptions};
+    use ndarray::{s, Array5};
+
+    #[test]
+    fn test_fit_recovers_multiplets() {
+        let x = Array1::linspace(2860.0, 2880.0, 201);
+        for hyperfine in [Hyperfine::N14, Hyperfine::N15] {
+            let model = HyperfineLorentzian::new(hyperfine, 1e6);
+            assert_gradient_matches(&model, &x, &[0.02, 0.4, 2871.3]);
+            assert_fit_recovers(&model, &x, &[0.02, 0.4, 2871.3]);
+        }
+    }
+
+    #[test]
+    fn test_esr_fit_places_lines_on_hz_axis() {
+        let n = 201;
+        let x = Array1::linspace(2.860e9, 2.880e9, n);
+        let truth = DVector::from_vec(vec![0.02e6, 0.4e6, 2.8713e9]);
+        let trace = x.mapv(|x| HyperfineLorentzian::new(Hyperfine::N14, 1.0).evaluate(x, &truth));
+        let mut data = Array5::zeros((1, n, 1, 1, 1));
+        data.slice_mut(s![0, .., 0, 0, 0]).assign(&trace);
+        let mut container = DataContainer::from_data(data.into_dyn());
+        container.set_axis(1, x, Some("Hz")).unwrap();
+
+        let model = EsrModel::Hyperfine(Hyperfine::N14);
+        let result = container
+            .esr_fit(model, None, &FitOptions::default())
+            .unwrap();
+        assert_eq!(result.units, ["Hz", "Hz", "Hz"]);
+        for (k, expected) in truth.iter().enumerate() {
+            let fitted = result.fit.params[[0, 0, k]];
+            assert!(
+                (fitted - expected).abs() < 1e-6 * expected.abs(),
+                "{} fitted as {}",
+                expected,
+                fitted
+            );
+        }
+
+        container.axis_units[1] = None;
+        assert!(matches!(
+            container.esr_fit(model, None, &FitOptions::default()),
+            Err(QufitError::Value(_))
+        ));
+    }
+}
@@ -0,0 +1,152 @@
+use ndarray::Array1;
+use std::str::FromStr;
+
+/// Loss applied to the scaled residuals `r / f_scale`, with the names used
+/// by `scipy.optimize.least_squares`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Loss {
+    /// Ordinary least squares.
+    Linear,
+    /// Quadratic for small residuals, linear beyond `f_scale`.
+    Huber,
+    /// Smooth approximation of the absolute value, `2 (sqrt(1 + z) - 1)`.
+    SoftL1,
+    /// `ln(1 + z)`, strongly suppresses outliers.
+    Cauchy,
+}
+
+impl Loss {
+    /// Derivative of the loss `rho(z)` with respect to the squared scaled
+    /// residual `z`, which is the weight of that residual in the next
+    /// iteration of iteratively reweighted least squares.
+    pub fn weight(&self, z: f64) -> f64 {
+        match self {
+            Loss::Linear => 1.0,
+            Loss::Huber => {
+                if z <= 1.0 {
+                    1.0
+                } else {
+                    1.0 / z.sqrt()
+                }
+            }
+            Loss::SoftL1 => 1.0 / (1.0 + z).sqrt(),
+            Loss::Cauchy => 1.0 / (1.0 + z),
+        }
+    }
+}
+
+impl FromStr for Loss {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "linear" => Ok(Loss::Linear),
+            "huber" => Ok(Loss::Huber),
+            "soft_l1" => Ok(Loss::SoftL1),
+            "cauchy" => Ok(Loss::Cauchy),
+            _ => Err(format!(
+                "Unknown loss '{}'. Available losses: linear, huber, soft_l1, cauchy",
+                s
+            )),
+        }
+    }
+}
+
+/// A loss and the residual at which it starts to deviate from least
+/// squares. Without `f_scale` the scale is estimated per trace from the
+/// median absolute deviation of the least squares residuals.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RobustLoss {
+    pub loss: Loss,
+    pub f_scale: Option<f64>,
+}
+
+impl Default for RobustLoss {
+    fn default() -> Self {
+        RobustLoss {
+            loss: Loss::Linear,
+            f_scale: None,
+        }
+    }
+}
+
+impl RobustLoss {
+    /// Maximum number of reweighting steps after the least squares fit.
+    pub const MAX_ITERATIONS: usize = 20;
+
+    pub fn is_linear(&self) -> bool {
+        self.loss == Loss::Linear
+    }
+
+    /// `f_scale`, or the standard deviation of normally distributed
+    /// residuals with the same median absolute deviation as `residuals`.
+    pub fn scale(&self, residuals: &Array1<f64>) -> f64 {
+        self.f_scale.unwrap_or_else(|| {
+            let mut deviations = residuals.mapv(f64::abs).to_vec();
+            deviations.sort_by(|a, b| a.total_cmp(b));
+            let n = deviations.len();
+            let median = match n {
+                0 => f64::NAN,
+                _ if n % 2 == 1 => deviations[n / 2],
+                _ => (deviations[n / 2 - 1] + deviations[n / 2]) / 2.0,
+            };
+            1.4826 * median
+        })
+    }
+
+    /// Factor of every residual in the next reweighting step.
+    pub fn residual_weights(&self, residuals: &Array1<f64>, scale: f64) -> Array1<f64> {
+        residuals.mapv(|r| self.loss.weight((r / scale).powi(2)).sqrt())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fit_esr_nalgebra::Lorentzian;
+    use crate::fit_model::{fit_trace, Constraints, FitModel};
+    use nalgebra::DVector;
+
+    #[test]
+    fn test_weights_follow_least_squares_near_zero() {
+        for loss in [Loss::Linear, Loss::Huber, Loss::SoftL1, Loss::Cauchy] {
+            assert!((loss.weight(0.0) - 1.0).abs() < 1e-12);
+            assert!(loss.weight(100.0) <= 1.0);
+        }
+        assert_eq!(Loss::Huber.weight(4.0), 0.5);
+        assert_eq!(Loss::Cauchy.weight(4.0), 0.2);
+        assert_eq!(Loss::from_str("Soft_L1"), Ok(Loss::SoftL1));
+        assert!(Loss::from_str("tukey").is_err());
+    }
+
+    #[test]
+    fn test_robust_fit_ignores_outlier() {
+        let x = Array1::linspace(0.0, 1.0, 101);
+        let truth = DVector::from_vec(vec![0.01, 0.05, 0.4]);
+        let mut y = x.mapv(|x| Lorentzian.evaluate(x, &truth));
+        // Small noise so that the residual scale can be estimated.
+        for (i, y) in y.iter_mut().enumerate() {
+            *y += 1e-4 * (i as f64 * 2.3).sin();
+        }
+        // A cosmic ray hit next to the resonance.
+        y[45] += 0.05;
+
+        let constraints = Constraints::new(&Lorentzian);
+        let plain = fit_trace(
+            &Lorentzian,
+            &constraints,
+            &RobustLoss::default(),
+            &x,
+            &y,
+            None,
+        );
+        let robust = RobustLoss {
+            loss: Loss::Cauchy,
+            f_scale: None,
+        };
+        let robust = fit_trace(&Lorentzian, &constraints, &robust, &x, &y, None);
+        let error = |params: &Array1<f64>| (params[2] - 0.4).abs();
+        assert!(error(&robust.params) < 1e-4);
+        assert!(error(&robust.params) < error(&plain.params) / 10.0);
+    }
+}
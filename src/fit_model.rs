@@ -0,0 +1,943 @@
+use crate::error::QufitError;
+use crate::fit_common::{minimize, pixel_traces, standard_errors, PixelFit};
+use crate::fit_loss::RobustLoss;
+use crate::fit_result::{FitResult, ParamUnit};
+use crate::load::DataContainer;
+use levenberg_marquardt::LeastSquaresProblem;
+use nalgebra::{DMatrix, DVector, Dyn, Owned};
+use ndarray::{s, Array1, ArrayD, ArrayView1};
+use std::collections::HashMap;
+
+/// A function of the sweep axis with a fixed number of free parameters.
+/// Implementing this trait is all that is needed to fit a new model to
+/// single traces with [`fit_trace`] or to whole images with
+/// [`DataContainer::fit_model_image`].
+pub trait FitModel: Sync {
+    /// Name stored in the [`FitResult`].
+    fn name(&self) -> String;
+
+    /// Names and units of the parameters, in the order they are fitted.
+    fn param_info(&self) -> Vec<(String, ParamUnit)>;
+
+    fn n_params(&self) -> usize {
+        self.param_info().len()
+    }
+
+    /// Value of the model at `x`.
+    fn evaluate(&self, x: f64, p: &DVector<f64>) -> f64;
+
+    /// Derivatives of [`FitModel::evaluate`] at `x` with respect to every
+    /// parameter.
+    fn gradient(&self, x: f64, p: &DVector<f64>) -> Vec<f64>;
+
+    /// Starting point of the optimizer for one trace.
+    fn initial_guess(&self, x_data: &Array1<f64>, y_data: &Array1<f64>) -> Vec<f64>;
+
+    /// Lower and upper bound of every parameter. Unbounded by default.
+    fn bounds(&self) -> Vec<(f64, f64)> {
+        vec![FREE; self.n_params()]
+    }
+}
+
+/// Bounds of a parameter without constraints.
+pub const FREE: (f64, f64) = (f64::NEG_INFINITY, f64::INFINITY);
+/// Bounds of a width, time or other non-negative parameter.
+pub const NON_NEGATIVE: (f64, f64) = (0.0, f64::INFINITY);
+
+/// `(lower, upper)` bounds by parameter name, `None` for an open side.
+pub type NamedBounds = HashMap<String, (Option<f64>, Option<f64>)>;
+
+/// Position of the parameter called `name` in the parameters of `model`.
+pub fn param_index<M: FitModel + ?Sized>(model: &M, name: &str) -> Result<usize, String> {
+    let names: Vec<String> = model
+        .param_info()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    names.iter().position(|n| n == name).ok_or_else(|| {
+        format!(
+            "{} has no parameter '{}'. Parameters: {}",
+            model.name(),
+            name,
+            names.join(", ")
+        )
+    })
+}
+
+/// Bounds and fixed values of the parameters of a model.
+///
+/// The optimizer works on unconstrained internal variables `u` that are
+/// mapped into the allowed range of every free parameter:
+/// - both bounds: `p = lower + (upper - lower) * (sin(u) + 1) / 2`, kept a
+///   tiny fraction inside the bounds
+/// - lower bound: `p = lower + sqrt(u^2 + 1) - 1`, kept a tiny distance
+///   above the bound
+/// - upper bound: `p = upper - sqrt(u^2 + 1) + 1`, kept a tiny distance
+///   below the bound
+///
+/// Fixed parameters are not part of `u` at all.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Constraints {
+    pub lower: Vec<f64>,
+    pub upper: Vec<f64>,
+    pub fixed: Vec<Option<f64>>,
+}
+
+impl Constraints {
+    /// The default bounds of `model`, with all parameters free.
+    pub fn new<M: FitModel + ?Sized>(model: &M) -> Self {
+        let (lower, upper) = model.bounds().into_iter().unzip();
+        Constraints {
+            lower,
+            upper,
+            fixed: vec![None; model.n_params()],
+        }
+    }
+
+    /// The default bounds of `model`, narrowed by `bounds` and with the
+    /// parameters in `fixed` held at the given values. The default bounds
+    /// still apply, so e.g. a width stays non-negative whatever its bounds.
+    /// Unknown names are a `Value` error, bounds that leave no room or are
+    /// NaN and fixed values that are not finite a `Fit` error.
+    pub fn from_names<M: FitModel + ?Sized>(
+        model: &M,
+        bounds: &NamedBounds,
+        fixed: &HashMap<String, f64>,
+    ) -> Result<Self, QufitError> {
+        let index_of = |name: &String| param_index(model, name).map_err(QufitError::Value);
+        let mut constraints = Self::new(model);
+        for (name, &(lower, upper)) in bounds {
+            let k = index_of(name)?;
+            if lower.is_some_and(f64::is_nan) || upper.is_some_and(f64::is_nan) {
+                return Err(QufitError::Fit(format!(
+                    "The bounds of '{}' cannot be NaN",
+                    name
+                )));
+            }
+            let lower = lower.map_or(constraints.lower[k], |l| l.max(constraints.lower[k]));
+            let upper = upper.map_or(constraints.upper[k], |u| u.min(constraints.upper[k]));
+            if lower >= upper {
+                return Err(QufitError::Fit(format!(
+                    "The lower bound of '{}' ({}) needs to be below its upper bound ({})",
+                    name, lower, upper
+                )));
+            }
+            if (upper - lower).is_infinite() && lower.is_finite() && upper.is_finite() {
+                return Err(QufitError::Fit(format!(
+                    "The bounds of '{}' are too far apart",
+                    name
+                )));
+            }
+            constraints.lower[k] = lower;
+            constraints.upper[k] = upper;
+        }
+        for (name, &value) in fixed {
+            let k = index_of(name)?;
+            if !value.is_finite() {
+                return Err(QufitError::Fit(format!(
+                    "'{}' cannot be fixed at {}",
+                    name, value
+                )));
+            }
+            constraints.fixed[k] = Some(value);
+        }
+        Ok(constraints)
+    }
+
+    /// Same fixed parameters, but without bounds.
+    pub(crate) fn without_bounds(&self) -> Self {
+        Constraints {
+            lower: vec![f64::NEG_INFINITY; self.lower.len()],
+            upper: vec![f64::INFINITY; self.upper.len()],
+            fixed: self.fixed.clone(),
+        }
+    }
+
+    pub(crate) fn free(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.fixed.len()).filter(|&k| self.fixed[k].is_none())
+    }
+
+    /// Internal variables of the free parameters in `p`. Values on or
+    /// outside a bound are moved inside, where the transformation is not
+    /// stationary.
+    pub fn to_internal(&self, p: &[f64]) -> DVector<f64> {
+        DVector::from_iterator(
+            self.free().count(),
+            self.free().map(|k| {
+                let (lower, upper) = (self.lower[k], self.upper[k]);
+                let inside = |bound: f64| 1e-3 * (p[k] - bound).abs().max(bound.abs()).max(1e-9);
+                match (lower.is_finite(), upper.is_finite()) {
+                    (true, true) => {
+                        let margin = 1e-3 * (upper - lower);
+                        let p = p[k].clamp(lower + margin, upper - margin);
+                        (2.0 * (p - lower) / (upper - lower) - 1.0).asin()
+                    }
+                    (true, false) => {
+                        let p = p[k].max(lower + inside(lower));
+                        ((p - lower) * (p - lower + 2.0)).sqrt()
+                    }
+                    (false, true) => {
+                        let p = p[k].min(upper - inside(upper));
+                        ((upper - p) * (upper - p + 2.0)).sqrt()
+                    }
+                    (false, false) => p[k],
+                }
+            }),
+        )
+    }
+
+    /// All model parameters for the internal variables `u`.
+    pub fn to_external(&self, u: &DVector<f64>) -> DVector<f64> {
+        let mut free = u.iter();
+        DVector::from_iterator(
+            self.fixed.len(),
+            (0..self.fixed.len()).map(|k| match self.fixed[k] {
+                Some(value) => value,
+                None => {
+                    let u = *free.next().unwrap();
+                    let (lower, upper) = (self.lower[k], self.upper[k]);
+                    match (lower.is_finite(), upper.is_finite()) {
+                        // Staying off the bounds themselves keeps a width
+                        // bounded below by zero from becoming zero.
+                        (true, true) => {
+                            let s = ((u.sin() + 1.0) / 2.0).clamp(Self::EDGE, 1.0 - Self::EDGE);
+                            lower + (upper - lower) * s
+                        }
+                        (true, false) => lower + Self::one_sided_off_bound(u, lower),
+                        (false, true) => upper - Self::one_sided_off_bound(u, upper),
+                        (false, false) => u,
+                    }
+                }
+            }),
+        )
+    }
+
+    /// Errors of all model parameters from those of the free parameters,
+    /// zero for the fixed ones.
+    pub fn all_errors(&self, free_errors: &[f64]) -> Array1<f64> {
+        let mut free_errors = free_errors.iter();
+        self.fixed
+            .iter()
+            .map(|fixed| match fixed {
+                Some(_) => 0.0,
+                None => *free_errors.next().unwrap(),
+            })
+            .collect()
+    }
+
+    /// Closest approach of a parameter to its bounds, relative to the
+    /// distance between two bounds or to the size of a single one.
+    const EDGE: f64 = 1e-12;
+
+    /// `sqrt(u^2 + 1) - 1`, written so that it does not lose precision for
+    /// small `u`.
+    fn one_sided(u: f64) -> f64 {
+        u.powi(2) / ((u.powi(2) + 1.0).sqrt() + 1.0)
+    }
+
+    /// Distance of a parameter with the single bound `bound` from it, at
+    /// least `EDGE` times the size of the bound, or `EDGE` for a bound at
+    /// zero.
+    fn one_sided_off_bound(u: f64, bound: f64) -> f64 {
+        Self::one_sided(u).max(Self::EDGE * bound.abs().max(1.0))
+    }
+
+    /// `dp / du` of every free parameter.
+    fn derivatives(&self, u: &DVector<f64>) -> Vec<f64> {
+        self.free()
+            .zip(u.iter())
+            .map(|(k, &u)| {
+                let (lower, upper) = (self.lower[k], self.upper[k]);
+                match (lower.is_finite(), upper.is_finite()) {
+                    (true, true) => (upper - lower) * u.cos() / 2.0,
+                    (true, false) => u / (u.powi(2) + 1.0).sqrt(),
+                    (false, true) => -u / (u.powi(2) + 1.0).sqrt(),
+                    (false, false) => 1.0,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Least squares problem of fitting `model` to one trace. `p` holds the
+/// internal variables of the free parameters, see [`Constraints`].
+pub struct ModelProblem<'a, M: FitModel + ?Sized> {
+    pub model: &'a M,
+    pub constraints: &'a Constraints,
+    pub x_data: &'a DVector<f64>,
+    pub y_data: &'a DVector<f64>,
+    /// Factor of every residual, `1 / sigma` for a weighted fit.
+    pub weights: &'a DVector<f64>,
+    pub p: DVector<f64>,
+}
+
+impl<M: FitModel + ?Sized> LeastSquaresProblem<f64, Dyn, Dyn> for ModelProblem<'_, M> {
+    type ParameterStorage = Owned<f64, Dyn>;
+    type ResidualStorage = Owned<f64, Dyn>;
+    type JacobianStorage = Owned<f64, Dyn, Dyn>;
+
+    fn set_params(&mut self, p: &DVector<f64>) {
+        self.p.copy_from(p)
+    }
+
+    fn params(&self) -> DVector<f64> {
+        self.p.clone()
+    }
+
+    fn residuals(&self) -> Option<DVector<f64>> {
+        let params = self.constraints.to_external(&self.p);
+        let residuals: Vec<f64> = (self.x_data.as_slice().iter())
+            .zip(self.y_data.as_slice())
+            .zip(self.weights.as_slice())
+            .map(|((&x, &y), &w)| w * (y - self.model.evaluate(x, &params)))
+            .collect();
+        Some(DVector::from_vec(residuals))
+    }
+
+    fn jacobian(&self) -> Option<DMatrix<f64>> {
+        let params = self.constraints.to_external(&self.p);
+        let derivatives = self.constraints.derivatives(&self.p);
+        let free: Vec<usize> = self.constraints.free().collect();
+        let mut jacobian = DMatrix::zeros(self.x_data.len(), self.p.len());
+        for (i, &x) in self.x_data.iter().enumerate() {
+            let gradient = self.model.gradient(x, &params);
+            // residual = w * (y - f), so the Jacobian is the negative
+            // weighted model gradient
+            for (j, (&k, dp_du)) in free.iter().zip(&derivatives).enumerate() {
+                jacobian[(i, j)] = -self.weights[i] * gradient[k] * dp_du;
+            }
+        }
+        Some(jacobian)
+    }
+}
+
+/// Fits `model` to a single trace, starting from its initial guess.
+/// With `sigma`, the standard deviation of every point, the residuals are
+/// weighted by `1 / sigma` and taken as absolute: the errors come from the
+/// unscaled covariance `(J^T W J)^-1`. Without it, the covariance is scaled
+/// by the reduced chi-square. A robust `loss` is minimized by iteratively
+/// reweighting the residuals, starting from the least squares fit.
+/// Parameters and errors are returned for all model parameters, fixed ones
+/// have an error of zero. Use a [`Workspace`] to fit many traces.
+pub fn fit_trace<M: FitModel + ?Sized>(
+    model: &M,
+    constraints: &Constraints,
+    loss: &RobustLoss,
+    x_data: &Array1<f64>,
+    y_data: &Array1<f64>,
+    sigma: Option<&Array1<f64>>,
+) -> PixelFit {
+    Workspace::new(y_data.len()).fit(model, constraints, loss, x_data, y_data, sigma)
+}
+
+/// [`fit_trace`] starting from the model parameters `init_param`.
+pub fn fit_trace_from<M: FitModel + ?Sized>(
+    model: &M,
+    constraints: &Constraints,
+    loss: &RobustLoss,
+    init_param: &[f64],
+    x_data: &Array1<f64>,
+    y_data: &Array1<f64>,
+    sigma: Option<&Array1<f64>>,
+) -> PixelFit {
+    Workspace::new(y_data.len()).fit_from(
+        model,
+        constraints,
+        loss,
+        init_param,
+        x_data,
+        y_data,
+        sigma,
+    )
+}
+
+/// The vectors a fit of one trace works on, kept from one trace to the
+/// next. Image fits give every thread its own.
+pub struct Workspace {
+    x: DVector<f64>,
+    y: DVector<f64>,
+    /// `1 / sigma`, or ones without `sigma`.
+    sigma_weights: DVector<f64>,
+    /// Weights of the current fit, including those of a robust loss.
+    weights: DVector<f64>,
+    /// Weighted residuals of the current fit.
+    residuals: Array1<f64>,
+}
+
+impl Workspace {
+    pub fn new(n_points: usize) -> Self {
+        Workspace {
+            x: DVector::zeros(n_points),
+            y: DVector::zeros(n_points),
+            sigma_weights: DVector::zeros(n_points),
+            weights: DVector::zeros(n_points),
+            residuals: Array1::zeros(n_points),
+        }
+    }
+
+    /// [`fit_trace`] in this workspace.
+    pub fn fit<M: FitModel + ?Sized>(
+        &mut self,
+        model: &M,
+        constraints: &Constraints,
+        loss: &RobustLoss,
+        x_data: &Array1<f64>,
+        y_data: &Array1<f64>,
+        sigma: Option<&Array1<f64>>,
+    ) -> PixelFit {
+        let init_param = model.initial_guess(x_data, y_data);
+        self.fit_from(model, constraints, loss, &init_param, x_data, y_data, sigma)
+    }
+
+    /// [`fit_trace_from`] in this workspace.
+    #[allow(clippy::too_many_arguments)]
+    pub fn fit_from<M: FitModel + ?Sized>(
+        &mut self,
+        model: &M,
+        constraints: &Constraints,
+        loss: &RobustLoss,
+        init_param: &[f64],
+        x_data: &Array1<f64>,
+        y_data: &Array1<f64>,
+        sigma: Option<&Array1<f64>>,
+    ) -> PixelFit {
+        if self.y.len() != y_data.len() {
+            *self = Workspace::new(y_data.len());
+        }
+        self.x.iter_mut().zip(x_data).for_each(|(a, &b)| *a = b);
+        self.y.iter_mut().zip(y_data).for_each(|(a, &b)| *a = b);
+        match sigma {
+            Some(sigma) => self
+                .sigma_weights
+                .iter_mut()
+                .zip(sigma)
+                .for_each(|(w, &s)| *w = 1.0 / s),
+            None => self.sigma_weights.fill(1.0),
+        }
+        let absolute_sigma = sigma.is_some();
+        let mut fit = fit_weighted(
+            model,
+            constraints,
+            &self.x,
+            &self.y,
+            &self.sigma_weights,
+            absolute_sigma,
+            init_param,
+        );
+        if loss.is_linear() {
+            return fit;
+        }
+
+        self.update_residuals(model, &fit.params);
+        let scale = loss.scale(&self.residuals);
+        if !(scale.is_finite() && scale > 0.0) {
+            return fit;
+        }
+        let mut evaluations = fit.n_evaluations;
+        for _ in 0..RobustLoss::MAX_ITERATIONS {
+            let loss_weights = loss.residual_weights(&self.residuals, scale);
+            for ((w, &sigma_weight), loss_weight) in self
+                .weights
+                .iter_mut()
+                .zip(&self.sigma_weights)
+                .zip(loss_weights)
+            {
+                *w = sigma_weight * loss_weight;
+            }
+            let next = fit_weighted(
+                model,
+                constraints,
+                &self.x,
+                &self.y,
+                &self.weights,
+                absolute_sigma,
+                fit.params.as_slice().unwrap(),
+            );
+            evaluations += next.n_evaluations;
+            let converged = next
+                .params
+                .iter()
+                .zip(fit.params.iter())
+                .all(|(new, old)| (new - old).abs() <= 1e-8 * old.abs().max(1e-12));
+            fit = next;
+            if converged {
+                break;
+            }
+            self.update_residuals(model, &fit.params);
+        }
+        fit.n_evaluations = evaluations;
+        fit
+    }
+
+    /// Residuals of the model parameters `params`, weighted by `1 / sigma`.
+    fn update_residuals<M: FitModel + ?Sized>(&mut self, model: &M, params: &Array1<f64>) {
+        let params = DVector::from_column_slice(params.as_slice().unwrap());
+        for (r, ((&x, &y), &w)) in self
+            .residuals
+            .iter_mut()
+            .zip(self.x.iter().zip(&self.y).zip(&self.sigma_weights))
+        {
+            *r = (y - model.evaluate(x, &params)) * w;
+        }
+    }
+}
+
+/// One Levenberg-Marquardt fit with fixed residual `weights`, starting
+/// from the model parameters `init_param`. With `absolute_sigma` the
+/// weights come from known uncertainties, see [`standard_errors`].
+fn fit_weighted<M: FitModel + ?Sized>(
+    model: &M,
+    constraints: &Constraints,
+    x: &DVector<f64>,
+    y: &DVector<f64>,
+    weights: &DVector<f64>,
+    absolute_sigma: bool,
+    init_param: &[f64],
+) -> PixelFit {
+    let problem = ModelProblem {
+        model,
+        constraints,
+        x_data: x,
+        y_data: y,
+        weights,
+        p: constraints.to_internal(init_param),
+    };
+    let mut fit = minimize(
+        problem,
+        ArrayView1::from(y.as_slice()),
+        ArrayView1::from(weights.as_slice()),
+        absolute_sigma,
+    );
+
+    // The covariance of the internal variables is singular at a bound, so
+    // the errors are evaluated for the model parameters directly.
+    let params =
+        constraints.to_external(&DVector::from_column_slice(fit.params.as_slice().unwrap()));
+    let unbounded = constraints.without_bounds();
+    let free_errors = standard_errors(
+        &ModelProblem {
+            model,
+            constraints: &unbounded,
+            x_data: x,
+            y_data: y,
+            weights,
+            p: unbounded.to_internal(params.as_slice()),
+        },
+        absolute_sigma,
+    );
+    fit.errors = constraints.all_errors(free_errors.as_slice().unwrap());
+    fit.params = Array1::from_vec(params.as_slice().to_vec());
+    fit
+}
+
+/// How the pixels of an image are fitted, apart from the model.
+#[derive(Clone, Debug)]
+pub struct FitSettings {
+    pub constraints: Constraints,
+    pub loss: RobustLoss,
+    /// Fit blocks of `coarse_bin` x `coarse_bin` averaged pixels first and
+    /// start every pixel from the result of its block.
+    pub coarse_bin: Option<usize>,
+}
+
+impl DataContainer {
+    /// Fits `model` to the trace along `sweep_dim` of every pixel, weighted
+    /// by `sigma` if given (same shape as the data). `axis_unit` is the unit
+    /// of the sweep axis, if known.
+    ///
+    /// With a coarse seed, pixels whose seeded fit fails are fitted again
+    /// from the initial guess of the model and keep the better result.
+    /// Fails if the data has no pixel axes or the traces are too short for
+    /// the free parameters.
+    pub fn fit_model_image<M: FitModel + ?Sized>(
+        &self,
+        model: &M,
+        settings: &FitSettings,
+        sweep_dim: usize,
+        sigma: Option<&ArrayD<f64>>,
+        axis_unit: Option<&str>,
+    ) -> Result<FitResult, QufitError> {
+        let FitSettings {
+            constraints, loss, ..
+        } = settings;
+        let n_params = model.n_params();
+        let n_points = pixel_traces(&self.data, sweep_dim)?.dim().0;
+        let n_free = constraints.free().count();
+        if n_points < n_free.max(1) {
+            return Err(QufitError::Fit(format!(
+                "The traces have {} points, fewer than the {} free parameters of {}",
+                n_points,
+                n_free,
+                model.name()
+            )));
+        }
+        let coarse = match settings.coarse_bin {
+            Some(bin) => {
+                let binned = self.binned(bin, sigma)?;
+                let fit = binned.fit_pixels(
+                    sweep_dim,
+                    n_params,
+                    binned.sigma.as_ref(),
+                    || Workspace::new(n_points),
+                    |workspace, _, x, y, sigma| {
+                        workspace.fit(model, constraints, loss, x, y, sigma)
+                    },
+                )?;
+                Some((bin, fit))
+            }
+            None => None,
+        };
+        let init = || Workspace::new(n_points);
+        let fit = self.fit_pixels(
+            sweep_dim,
+            n_params,
+            sigma,
+            init,
+            |workspace, (i, j), x, y, sigma| {
+                let seed = coarse.as_ref().and_then(|(bin, fit)| {
+                    let block = (i / bin, j / bin);
+                    fit.is_success(block)
+                        .then(|| fit.params.slice(s![block.0, block.1, ..]).to_vec())
+                });
+                let Some(seed) = seed else {
+                    return workspace.fit(model, constraints, loss, x, y, sigma);
+                };
+                let seeded = workspace.fit_from(model, constraints, loss, &seed, x, y, sigma);
+                if seeded.status.is_success() {
+                    return seeded;
+                }
+                let fallback = workspace.fit(model, constraints, loss, x, y, sigma);
+                let evaluations = seeded.n_evaluations + fallback.n_evaluations;
+                let mut best = if seeded.reduced_chi2 < fallback.reduced_chi2 {
+                    seeded
+                } else {
+                    fallback
+                };
+                best.n_evaluations = evaluations;
+                best
+            },
+        )?;
+        Ok(FitResult::new(
+            &model.name(),
+            model.param_info(),
+            axis_unit,
+            fit,
+        ))
+    }
+}
+
+/// Compares the analytic gradient of `model` with central differences.
+#[cfg(test)]
+pub fn assert_gradient_matches<M: FitModel + ?Sized>(model: &M, x_data: &Array1<f64>, p: &[f64]) {
+    let p = DVector::from_vec(p.to_vec());
+    let step = 1e-6;
+    for &x in x_data.iter() {
+        let gradient = model.gradient(x, &p);
+        assert_eq!(gradient.len(), model.n_params());
+        for (k, analytic) in gradient.into_iter().enumerate() {
+            let mut upper = p.clone();
+            let mut lower = p.clone();
+            upper[k] += step;
+            lower[k] -= step;
+            let numeric = (model.evaluate(x, &upper) - model.evaluate(x, &lower)) / (2.0 * step);
+            let tolerance = 1e-5 * analytic.abs().max(1.0);
+            assert!(
+                (numeric - analytic).abs() < tolerance,
+                "{}: d/dp{} at x = {}: {} != {}",
+                model.name(),
+                k,
+                x,
+                numeric,
+                analytic
+            );
+        }
+    }
+}
+
+/// Fits noiseless data generated from `truth` and checks that it is recovered.
+#[cfg(test)]
+pub fn assert_fit_recovers<M: FitModel + ?Sized>(model: &M, x_data: &Array1<f64>, truth: &[f64]) {
+    let truth = DVector::from_vec(truth.to_vec());
+    let y_data = x_data.mapv(|x| model.evaluate(x, &truth));
+    let result = fit_trace(
+        model,
+        &Constraints::new(model),
+        &RobustLoss::default(),
+        x_data,
+        &y_data,
+        None,
+    );
+    for (fitted, expected) in result.params.iter().zip(truth.iter()) {
+        assert!(
+            (fitted - expected).abs() < 1e-6,
+            "{}: fitted {} instead of {}",
+            model.name(),
+            fitted,
+            expected
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fit_esr_nalgebra::{EsrModel, Lorentzian};
+    use crate::fit_loss::Loss;
+    use crate::fit_multi_esr_nalgebra::MultiLorentzian;
+    use crate::fit_rabi_nalgebra::{DampedRabi, Envelope, GuessStrategy, Rabi};
+    use crate::fit_t1_nalgebra::StretchedExponential;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_models_agree_on_parameter_count() {
+        let strategy = GuessStrategy::Fft;
+        let mut models: Vec<Box<dyn FitModel>> =
+            ["lorentzian", "gaussian", "voigt", "sinc2", "n14", "n15"]
+                .iter()
+                .map(|name| {
+                    EsrModel::from_str(name)
+                        .unwrap()
+                        .fit_model(Some("MHz"))
+                        .unwrap()
+                })
+                .collect();
+        for shared_width in [false, true] {
+            models.push(Box::new(MultiLorentzian {
+                n_peaks: 3,
+                shared_width,
+            }));
+        }
+        models.push(Box::new(Rabi { strategy }));
+        for envelope in [
+            Envelope::Exponential,
+            Envelope::Gaussian,
+            Envelope::Stretched,
+        ] {
+            models.push(Box::new(DampedRabi { envelope, strategy }));
+        }
+        models.push(Box::new(StretchedExponential));
+
+        let x = Array1::linspace(2860.0, 2880.0, 64);
+        let y = x.mapv(|x: f64| 1.0 - 0.01 * (x / 3.0).cos());
+        for model in models {
+            let n_params = model.n_params();
+            let p = DVector::from_vec(model.initial_guess(&x, &y));
+            assert_eq!(model.param_info().len(), n_params, "{}", model.name());
+            assert_eq!(p.len(), n_params, "{}", model.name());
+            assert_eq!(model.gradient(x[1], &p).len(), n_params, "{}", model.name());
+            assert_eq!(model.bounds().len(), n_params, "{}", model.name());
+        }
+    }
+
+    #[test]
+    fn test_internal_variables_round_trip() {
+        let constraints = Constraints {
+            lower: vec![0.0, f64::NEG_INFINITY, -1.0, f64::NEG_INFINITY],
+            upper: vec![f64::INFINITY, 2.0, 1.0, f64::INFINITY],
+            fixed: vec![None, None, None, Some(3.0)],
+        };
+        let p = [0.3, 1.5, -0.2, 7.0];
+        let u = constraints.to_internal(&p);
+        assert_eq!(u.len(), 3);
+        let back = constraints.to_external(&u);
+        for (k, expected) in [0.3, 1.5, -0.2, 3.0].iter().enumerate() {
+            assert!((back[k] - expected).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_one_sided_bounds_stay_off_the_bound() {
+        // Every ESR width is non-negative, and a width of zero gives NaN
+        // residuals.
+        let constraints = Constraints::new(&Lorentzian);
+        assert_eq!(constraints.lower[1], NON_NEGATIVE.0);
+        let p = constraints.to_external(&DVector::from_vec(vec![0.0, 0.0, 0.0]));
+        assert!(p[1] > 0.0);
+        assert!(Lorentzian.evaluate(0.4, &p).is_finite());
+
+        let constraints = Constraints {
+            lower: vec![f64::NEG_INFINITY],
+            upper: vec![-2.0],
+            fixed: vec![None],
+        };
+        let p = constraints.to_external(&DVector::from_vec(vec![0.0]));
+        assert!(p[0] < -2.0 && p[0] > -2.0 - 1e-9);
+    }
+
+    #[test]
+    fn test_fixed_and_bounded_parameters() {
+        let x = Array1::linspace(0.0, 1.0, 101);
+        let truth = DVector::from_vec(vec![0.01, 0.05, 0.4]);
+        let y = x.mapv(|x| Lorentzian.evaluate(x, &truth));
+
+        let fixed = HashMap::from([("gamma".to_string(), 0.05)]);
+        let constraints =
+            Constraints::from_names(&Lorentzian, &NamedBounds::new(), &fixed).unwrap();
+        let result = fit_trace(
+            &Lorentzian,
+            &constraints,
+            &RobustLoss::default(),
+            &x,
+            &y,
+            None,
+        );
+        assert_eq!(result.params[1], 0.05);
+        assert_eq!(result.errors[1], 0.0);
+        assert!((result.params[0] - 0.01).abs() < 1e-6);
+        assert!((result.params[2] - 0.4).abs() < 1e-6);
+
+        let bounds = NamedBounds::from([("a".to_string(), (None, Some(0.008)))]);
+        let constraints = Constraints::from_names(&Lorentzian, &bounds, &HashMap::new()).unwrap();
+        let result = fit_trace(
+            &Lorentzian,
+            &constraints,
+            &RobustLoss::default(),
+            &x,
+            &y,
+            None,
+        );
+        assert!(result.params[0] <= 0.008);
+        assert!(result.params[0] > 0.007);
+
+        let unknown = HashMap::from([("width".to_string(), 0.05)]);
+        assert!(Constraints::from_names(&Lorentzian, &NamedBounds::new(), &unknown).is_err());
+    }
+
+    #[test]
+    fn test_invalid_bounds_are_fit_errors() {
+        let no_fixed = HashMap::new();
+        for bounds in [
+            (Some(0.5), Some(0.1)),
+            (Some(f64::NAN), None),
+            (None, Some(f64::NAN)),
+            (Some(-f64::MAX), Some(f64::MAX)),
+        ] {
+            let bounds = NamedBounds::from([("x0".to_string(), bounds)]);
+            let result = Constraints::from_names(&Lorentzian, &bounds, &no_fixed);
+            assert!(
+                matches!(result, Err(QufitError::Fit(_))),
+                "{:?}: {:?}",
+                bounds,
+                result
+            );
+        }
+        // gamma cannot be negative, so this leaves no room.
+        let bounds = NamedBounds::from([("gamma".to_string(), (None, Some(-1.0)))]);
+        assert!(matches!(
+            Constraints::from_names(&Lorentzian, &bounds, &no_fixed),
+            Err(QufitError::Fit(_))
+        ));
+        let fixed = HashMap::from([("a".to_string(), f64::NAN)]);
+        assert!(matches!(
+            Constraints::from_names(&Lorentzian, &NamedBounds::new(), &fixed),
+            Err(QufitError::Fit(_))
+        ));
+
+        // A lower bound of zero is never reached at the edge of the map.
+        let bounds = NamedBounds::from([("gamma".to_string(), (Some(-1.0), Some(1.0)))]);
+        let constraints = Constraints::from_names(&Lorentzian, &bounds, &no_fixed).unwrap();
+        assert_eq!((constraints.lower[1], constraints.upper[1]), (0.0, 1.0));
+        let u = DVector::from_vec(vec![0.0, -std::f64::consts::FRAC_PI_2, 0.0]);
+        assert!(constraints.to_external(&u)[1] > 0.0);
+    }
+
+    #[test]
+    fn test_sigma_weights_residuals() {
+        let x = Array1::linspace(0.0, 1.0, 101);
+        let truth = DVector::from_vec(vec![0.01, 0.05, 0.4]);
+        let mut y = x.mapv(|x| Lorentzian.evaluate(x, &truth));
+        // A glitch on the resonance, flagged by a large uncertainty.
+        y[40] += 0.02;
+        let mut sigma = Array1::from_elem(101, 1e-3);
+        sigma[40] = 1e3;
+
+        let constraints = Constraints::new(&Lorentzian);
+        let unweighted = fit_trace(
+            &Lorentzian,
+            &constraints,
+            &RobustLoss::default(),
+            &x,
+            &y,
+            None,
+        );
+        let weighted = fit_trace(
+            &Lorentzian,
+            &constraints,
+            &RobustLoss::default(),
+            &x,
+            &y,
+            Some(&sigma),
+        );
+        // The glitch makes the unweighted dip shallower and wider.
+        assert!((unweighted.params[1] - 0.05).abs() > 1e-3);
+        for (fitted, expected) in weighted.params.iter().zip(truth.iter()) {
+            assert!((fitted - expected).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_workspace_matches_fresh_fits() {
+        let trace = |n: usize, x0: f64| {
+            let x = Array1::linspace(0.0, 1.0, n);
+            let truth = DVector::from_vec(vec![0.01, 0.05, x0]);
+            let mut y = x.mapv(|x| Lorentzian.evaluate(x, &truth));
+            y[n / 3] += 0.02;
+            (x, y)
+        };
+        let constraints = Constraints::new(&Lorentzian);
+        let cauchy = RobustLoss {
+            loss: Loss::Cauchy,
+            f_scale: None,
+        };
+        let sigma = Array1::from_elem(101, 1e-3);
+        let mut workspace = Workspace::new(101);
+        // A weighted robust fit leaves its weights in the workspace, the
+        // unweighted fits after it and the longer trace must not see them.
+        for (n, x0, sigma, loss) in [
+            (101, 0.4, Some(&sigma), &cauchy),
+            (101, 0.5, None, &cauchy),
+            (151, 0.6, None, &RobustLoss::default()),
+        ] {
+            let (x, y) = trace(n, x0);
+            let reused = workspace.fit(&Lorentzian, &constraints, loss, &x, &y, sigma);
+            let fresh = fit_trace(&Lorentzian, &constraints, loss, &x, &y, sigma);
+            assert_eq!(reused.params, fresh.params);
+            assert_eq!(reused.errors, fresh.errors);
+            assert_eq!(reused.n_evaluations, fresh.n_evaluations);
+        }
+    }
+
+    #[test]
+    fn test_coarse_seed_rescues_pixel_with_spike() {
+        let n = 101;
+        let x = Array1::linspace(0.0, 1.0, n);
+        let truth = DVector::from_vec(vec![0.01, 0.05, 0.4]);
+        let trace = x.mapv(|x| Lorentzian.evaluate(x, &truth));
+        let mut data = ndarray::Array5::zeros((1, n, 1, 4, 4));
+        for i in 0..4 {
+            for j in 0..4 {
+                data.slice_mut(s![0, .., 0, i, j]).assign(&trace);
+            }
+        }
+        // A spike deeper than the resonance moves the initial guess of this
+        // pixel, but averages out in its 2 x 2 block.
+        data[[0, 80, 0, 1, 1]] -= 0.1;
+        let container = DataContainer::from_data(data.into_dyn());
+
+        let mut settings = FitSettings {
+            constraints: Constraints::new(&Lorentzian),
+            loss: RobustLoss::default(),
+            coarse_bin: None,
+        };
+        let independent = container
+            .fit_model_image(&Lorentzian, &settings, 1, None, None)
+            .unwrap();
+        settings.coarse_bin = Some(2);
+        let seeded = container
+            .fit_model_image(&Lorentzian, &settings, 1, None, None)
+            .unwrap();
+        assert!((independent.fit.params[[1, 1, 2]] - 0.4).abs() > 0.1);
+        assert!((seeded.fit.params[[1, 1, 2]] - 0.4).abs() < 1e-3);
+        assert!((seeded.fit.params[[3, 3, 2]] - 0.4).abs() < 1e-6);
+    }
+}
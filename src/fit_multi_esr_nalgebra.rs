@@ -0,0 +1,228 @@
+use crate::fit_model::{FitModel, FREE, NON_NEGATIVE};
+use crate::fit_result::ParamUnit;
+use nalgebra::DVector;
+use ndarray::{s, Array1};
+use std::f64::consts::PI;
+
+/// Sum of `n_peaks` Lorentzian dips below a free baseline.
+///
+/// Parameter layout:
+/// - individual widths: `[baseline, a_0, gamma_0, x0_0, a_1, gamma_1, x0_1, ...]`
+/// - shared width: `[baseline, gamma, a_0, x0_0, a_1, x0_1, ...]`
+#[derive(Clone, Copy, Debug)]
+pub struct MultiLorentzian {
+    pub n_peaks: usize,
+    pub shared_width: bool,
+}
+
+impl MultiLorentzian {
+    /// Returns `(a, gamma, x0)` of the k-th dip.
+    fn peak(&self, params: &DVector<f64>, k: usize) -> (f64, f64, f64) {
+        if self.shared_width {
+            (params[2 + 2 * k], params[1], params[3 + 2 * k])
+        } else {
+            (params[1 + 3 * k], params[2 + 3 * k], params[3 + 3 * k])
+        }
+    }
+}
+
+impl FitModel for MultiLorentzian {
+    fn name(&self) -> String {
+        "multi_lorentzian".to_string()
+    }
+
+    fn param_info(&self) -> Vec<(String, ParamUnit)> {
+        let mut params = vec![("baseline".to_string(), ParamUnit::None)];
+        if self.shared_width {
+            params.push(("gamma".to_string(), ParamUnit::Axis));
+        }
+        for k in 0..self.n_peaks {
+            params.push((format!("a_{}", k), ParamUnit::Axis));
+            if !self.shared_width {
+                params.push((format!("gamma_{}", k), ParamUnit::Axis));
+            }
+            params.push((format!("x0_{}", k), ParamUnit::Axis));
+        }
+        params
+    }
+
+    fn n_params(&self) -> usize {
+        if self.shared_width {
+            2 + 2 * self.n_peaks
+        } else {
+            1 + 3 * self.n_peaks
+        }
+    }
+
+    fn evaluate(&self, x: f64, p: &DVector<f64>) -> f64 {
+        let dips: f64 = (0..self.n_peaks)
+            .map(|k| {
+                let (a, gamma, x0) = self.peak(p, k);
+                a / PI * gamma / ((x - x0).powi(2) + gamma.powi(2))
+            })
+            .sum();
+        p[0] - dips
+    }
+
+    fn gradient(&self, x: f64, p: &DVector<f64>) -> Vec<f64> {
+        let mut gradient = vec![0.0; self.n_params()];
+        gradient[0] = 1.0;
+        for k in 0..self.n_peaks {
+            let (a, gamma, x0) = self.peak(p, k);
+            let denom = (x - x0).powi(2) + gamma.powi(2);
+            let dl_da = 1.0 / PI * gamma / denom;
+            let dl_dgamma = a / PI * ((x - x0).powi(2) - gamma.powi(2)) / denom.powi(2);
+            let dl_dx0 = 2.0 * a / PI * ((x - x0) * gamma) / denom.powi(2);
+            if self.shared_width {
+                gradient[1] -= dl_dgamma;
+                gradient[2 + 2 * k] = -dl_da;
+                gradient[3 + 2 * k] = -dl_dx0;
+            } else {
+                gradient[1 + 3 * k] = -dl_da;
+                gradient[2 + 3 * k] = -dl_dgamma;
+                gradient[3 + 3 * k] = -dl_dx0;
+            }
+        }
+        gradient
+    }
+
+    /// Only the widths are bounded.
+    fn bounds(&self) -> Vec<(f64, f64)> {
+        self.param_info()
+            .iter()
+            .map(|(name, _)| {
+                if name.starts_with("gamma") {
+                    NON_NEGATIVE
+                } else {
+                    FREE
+                }
+            })
+            .collect()
+    }
+
+    fn initial_guess(&self, x_data: &Array1<f64>, y_data: &Array1<f64>) -> Vec<f64> {
+        initial_guess(x_data, y_data, self.n_peaks, self.shared_width)
+    }
+}
+
+/// Finds the indices of the `n_peaks` deepest, well separated local minima,
+/// ordered along the sweep. Missing dips are spread evenly over the sweep.
+fn find_dips(y_data: &Array1<f64>, n_peaks: usize) -> Vec<usize> {
+    let len = y_data.len();
+    let smoothed: Vec<f64> = (0..len)
+        .map(|i| {
+            let window = y_data.slice(s![i.saturating_sub(1)..(i + 2).min(len)]);
+            window.sum() / window.len() as f64
+        })
+        .collect();
+    let mut minima: Vec<usize> = (0..len)
+        .filter(|&i| {
+            (i == 0 || smoothed[i] <= smoothed[i - 1])
+                && (i == len - 1 || smoothed[i] <= smoothed[i + 1])
+        })
+        .collect();
+    minima.sort_by(|&a, &b| smoothed[a].total_cmp(&smoothed[b]));
+
+    let min_distance = (len / (4 * n_peaks)).max(1);
+    let mut dips: Vec<usize> = Vec::with_capacity(n_peaks);
+    for candidate in minima {
+        if dips.len() == n_peaks {
+            break;
+        }
+        if dips.iter().all(|&d| d.abs_diff(candidate) >= min_distance) {
+            dips.push(candidate);
+        }
+    }
+    for k in dips.len()..n_peaks {
+        dips.push((2 * k + 1) * len / (2 * n_peaks));
+    }
+    dips.sort();
+    dips
+}
+
+/// Half width at half depth of the dip at `idx`, measured on the data.
+fn estimate_width(x_data: &Array1<f64>, y_data: &Array1<f64>, idx: usize, baseline: f64) -> f64 {
+    let level = (baseline + y_data[idx]) / 2.0;
+    let right = (idx..y_data.len()).find(|&j| y_data[j] >= level);
+    let left = (0..=idx).rev().find(|&j| y_data[j] >= level);
+    let step = (x_data[x_data.len() - 1] - x_data[0]).abs() / (x_data.len() - 1) as f64;
+    let width = match (left, right) {
+        (Some(l), Some(r)) => (x_data[r] - x_data[l]).abs() / 2.0,
+        (Some(j), None) | (None, Some(j)) => (x_data[j] - x_data[idx]).abs(),
+        (None, None) => 0.0,
+    };
+    width.max(step)
+}
+
+pub fn initial_guess(
+    x_data: &Array1<f64>,
+    y_data: &Array1<f64>,
+    n_peaks: usize,
+    shared_width: bool,
+) -> Vec<f64> {
+    let mut sorted = y_data.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let baseline = sorted[sorted.len() / 2];
+
+    let dips = find_dips(y_data, n_peaks);
+    let widths: Vec<f64> = dips
+        .iter()
+        .map(|&idx| estimate_width(x_data, y_data, idx, baseline))
+        .collect();
+    let mean_width = widths.iter().sum::<f64>() / n_peaks as f64;
+
+    let mut init_param = vec![baseline];
+    if shared_width {
+        init_param.push(mean_width);
+    }
+    for (&idx, &width) in dips.iter().zip(widths.iter()) {
+        let gamma = if shared_width { mean_width } else { width };
+        // The depth of a Lorentzian dip is a / (pi * gamma).
+        let amplitude = (baseline - y_data[idx]).max(0.0) * PI * gamma;
+        init_param.push(amplitude);
+        if !shared_width {
+            init_param.push(gamma);
+        }
+        init_param.push(x_data[idx]);
+    }
+    init_param
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fit_model::{assert_fit_recovers, assert_gradient_matches};
+
+    fn truth(shared_width: bool) -> Vec<f64> {
+        if shared_width {
+            vec![1.0, 0.01, 0.0006, 0.3, 0.0004, 0.7]
+        } else {
+            vec![1.0, 0.0006, 0.01, 0.3, 0.0004, 0.015, 0.7]
+        }
+    }
+
+    #[test]
+    fn test_gradient_matches_finite_differences() {
+        let x = Array1::linspace(0.0, 1.0, 101);
+        for shared_width in [false, true] {
+            let model = MultiLorentzian {
+                n_peaks: 2,
+                shared_width,
+            };
+            let p: Vec<f64> = truth(shared_width).iter().map(|p| p * 1.1).collect();
+            assert_gradient_matches(&model, &x, &p);
+        }
+    }
+
+    #[test]
+    fn test_fit_recovers_two_dips() {
+        let x = Array1::linspace(0.0, 1.0, 201);
+        for shared_width in [false, true] {
+            let model = MultiLorentzian {
+                n_peaks: 2,
+                shared_width,
+            };
+            assert_fit_recovers(&model, &x, &truth(shared_width));
+        }
+    }
+}
@@ -1,177 +1,365 @@
-use crate::load::DataContainer;
+use crate::fft::dominant_oscillation;
+use crate::fit_model::{FitModel, FREE, NON_NEGATIVE};
+use crate::fit_result::{named, ParamUnit};
 use argmm::generic::simple_argmin;
-use levenberg_marquardt::{LeastSquaresProblem, LevenbergMarquardt};
-use nalgebra::{DMatrix, DVector, Dyn, Owned};
-use ndarray::{array, s, Array, Array1, Array3};
-use rayon::prelude::*;
-use std::sync::Mutex;
-
-fn rabi(t: f64, params: &DVector<f64>) -> f64 {
-    let o = params[0];
-    let a = params[1];
-    let tau = params[2];
-    let phi = params[3];
-    let cos_arg = std::f64::consts::PI / tau * t + phi;
-    a * cos_arg.cos() + o
-}
+use nalgebra::DVector;
+use ndarray::Array1;
+use std::f64::consts::PI;
+use std::str::FromStr;
 
-#[derive(Clone, Debug)]
-pub struct RabiFit {
-    pub x_data: DVector<f64>,
-    pub y_data: DVector<f64>,
-    pub p: DVector<f64>,
+/// Decay envelope of a damped Rabi oscillation, `exp(-(|t| / t_decay)^n)`.
+/// The envelope is symmetric so that sweeps starting before `t = 0`, e.g.
+/// with a trigger delay, stay defined for non-integer `n`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Envelope {
+    /// n = 1
+    Exponential,
+    /// n = 2
+    Gaussian,
+    /// n is a free fit parameter.
+    Stretched,
 }
 
-impl LeastSquaresProblem<f64, Dyn, Dyn> for RabiFit {
-    type ParameterStorage = Owned<f64, Dyn>;
-    type ResidualStorage = Owned<f64, Dyn>;
-    type JacobianStorage = Owned<f64, Dyn, Dyn>;
+impl Envelope {
+    pub fn n_params(&self) -> usize {
+        match self {
+            Envelope::Stretched => 6,
+            _ => 5,
+        }
+    }
 
-    fn set_params(&mut self, p: &DVector<f64>) {
-        self.p.copy_from(p)
+    pub fn name(&self) -> &'static str {
+        match self {
+            Envelope::Exponential => "exponential",
+            Envelope::Gaussian => "gaussian",
+            Envelope::Stretched => "stretched",
+        }
     }
 
-    fn params(&self) -> DVector<f64> {
-        self.p.clone()
+    fn exponent(&self, params: &DVector<f64>) -> f64 {
+        match self {
+            Envelope::Exponential => 1.0,
+            Envelope::Gaussian => 2.0,
+            Envelope::Stretched => params[5],
+        }
     }
+}
 
-    fn residuals(&self) -> Option<DVector<f64>> {
-        let residuals: DVector<f64> = &self.y_data - self.x_data.map(|x| rabi(x, &self.params()));
-        Some(residuals)
+impl FromStr for Envelope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "exponential" => Ok(Envelope::Exponential),
+            "gaussian" => Ok(Envelope::Gaussian),
+            "stretched" => Ok(Envelope::Stretched),
+            _ => Err(format!(
+                "Unknown damping '{}'. Available envelopes: exponential, gaussian, stretched",
+                s
+            )),
+        }
+    }
+}
+
+/// How the starting point of the Rabi fits is estimated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GuessStrategy {
+    /// Minimum of the linearly tapered trace, only reliable for traces
+    /// covering about one period.
+    Argmin,
+    /// Dominant frequency and phase of the Fourier spectrum of the trace.
+    Fft,
+}
+
+impl FromStr for GuessStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "argmin" => Ok(GuessStrategy::Argmin),
+            "fft" => Ok(GuessStrategy::Fft),
+            _ => Err(format!(
+                "Unknown guess strategy '{}'. Available strategies: fft, argmin",
+                s
+            )),
+        }
     }
+}
 
-    fn jacobian(&self) -> Option<DMatrix<f64>> {
-        let dl_do = &self.x_data.map(|x| self.gradient_do(x));
-        let dl_da = &self.x_data.map(|x| self.gradient_da(x));
-        let dl_dtau = &self.x_data.map(|x| self.gradient_dtau(x));
-        let dl_dphi = &self.x_data.map(|x| self.gradient_dphi(x));
-        let jacobian = DMatrix::from_columns(&[
-            dl_do.to_owned(),
-            dl_da.to_owned(),
-            dl_dtau.to_owned(),
-            dl_dphi.to_owned(),
-        ]);
-        Some(jacobian)
+/// `[offset, amplitude, tau, phi]` of the undamped oscillation.
+fn initial_guess(x_data: &Array1<f64>, y_data: &Array1<f64>, strategy: GuessStrategy) -> Vec<f64> {
+    let x_start = x_data[0];
+    let offset = y_data.mean().unwrap_or(1.0);
+    match strategy {
+        GuessStrategy::Argmin => {
+            let span = x_data[x_data.len() - 1] - x_start;
+            let x_normalised = (x_data - x_start) / span;
+            let y_data_tapered = (y_data - offset) * (-0.4 * &x_normalised + 1.0);
+            let tau_guess = x_data[simple_argmin(&y_data_tapered.to_vec())] - x_start;
+            vec![offset, 0.05, tau_guess, 0.0]
+        }
+        GuessStrategy::Fft => {
+            let (frequency, amplitude, phase) = dominant_oscillation(x_data, y_data);
+            // pi / tau * t = 2 pi f t, and the FFT phase refers to the first
+            // sample. The phase is wrapped to (-pi, pi] for axes far from zero.
+            let tau = 1.0 / (2.0 * frequency);
+            let phi = PI - (PI - phase + PI / tau * x_start).rem_euclid(2.0 * PI);
+            vec![offset, amplitude, tau, phi]
+        }
     }
 }
 
-impl RabiFit {
-    fn gradient_do(&self, _t: f64) -> f64 {
-        -1.0
+/// Undamped Rabi oscillation `offset + amplitude * cos(pi / tau * t + phi)`.
+#[derive(Clone, Copy, Debug)]
+pub struct Rabi {
+    pub strategy: GuessStrategy,
+}
+
+impl FitModel for Rabi {
+    fn name(&self) -> String {
+        "rabi".to_string()
     }
 
-    fn gradient_da(&self, t: f64) -> f64 {
-        let params = self.params();
-        let tau = params[2];
-        let phi = params[3];
-        let pi = std::f64::consts::PI;
+    fn param_info(&self) -> Vec<(String, ParamUnit)> {
+        use ParamUnit::{Axis, None, Radian};
+        named(&[
+            ("offset", None),
+            ("amplitude", None),
+            ("tau", Axis),
+            ("phi", Radian),
+        ])
+    }
 
-        let cos_arg = pi / tau * t + phi;
-        let df_da = cos_arg.cos();
-        -1.0 * df_da
+    fn evaluate(&self, t: f64, p: &DVector<f64>) -> f64 {
+        let o = p[0];
+        let a = p[1];
+        let tau = p[2];
+        let phi = p[3];
+        let cos_arg = PI / tau * t + phi;
+        a * cos_arg.cos() + o
     }
 
-    fn gradient_dtau(&self, t: f64) -> f64 {
-        let params = self.params();
-        let a = params[1];
-        let tau = params[2];
-        let phi = params[3];
-        let pi = std::f64::consts::PI;
+    fn gradient(&self, t: f64, p: &DVector<f64>) -> Vec<f64> {
+        let a = p[1];
+        let tau = p[2];
+        let phi = p[3];
+        let arg = PI / tau * t + phi;
 
-        let sin_arg = pi / tau * t + phi;
-        let df_dtau = pi / tau.powi(2) * t * a * sin_arg.sin();
-        -1.0 * df_dtau
+        let df_da = arg.cos();
+        let df_dtau = PI / tau.powi(2) * t * a * arg.sin();
+        let df_dphi = -a * arg.sin();
+        vec![1.0, df_da, df_dtau, df_dphi]
     }
 
-    fn gradient_dphi(&self, t: f64) -> f64 {
-        let params = self.params();
-        let a = params[1];
-        let tau = params[2];
-        let phi = params[3];
-        let pi = std::f64::consts::PI;
+    fn bounds(&self) -> Vec<(f64, f64)> {
+        vec![FREE, FREE, NON_NEGATIVE, FREE]
+    }
 
-        let sin_arg = pi / tau * t + phi;
-        let df_dphi = -1.0 * a * sin_arg.sin();
-        -1.0 * df_dphi
+    fn initial_guess(&self, x_data: &Array1<f64>, y_data: &Array1<f64>) -> Vec<f64> {
+        initial_guess(x_data, y_data, self.strategy)
     }
 }
 
-fn fit(x_data: Array1<f64>, y_data: Array1<f64>) -> Option<Array1<f64>> {
-    let y_data_tapered = (&y_data - y_data.mean().unwrap_or(1.0).clone()) * (-0.4 * &x_data + 1.0);
-    let tau_guess = simple_argmin(&y_data_tapered.to_vec()) as f64 / y_data.len() as f64;
-    let offset = y_data.mean().unwrap_or(1.0);
-    let init_param = vec![offset, 0.05, tau_guess, 0.0];
-    let x = DVector::from_vec(x_data.to_vec());
-    let data = DVector::from_vec(y_data.to_vec());
-
-    let problem = RabiFit {
-        x_data: x,
-        y_data: data,
-        p: DVector::from_vec(init_param),
-    };
-
-    let (result, report) = LevenbergMarquardt::new().minimize(problem);
-    let opt_params = result.p;
-    let opt_params: Array1<f64> =
-        Array1::from_shape_vec(opt_params.nrows(), opt_params.data.into()).unwrap();
-    if report.termination.was_successful() {
-        return Some(opt_params);
-    } else {
-        return None;
+/// Rabi oscillation with a decay envelope. The parameters are
+/// `[offset, amplitude, tau, phi, t_decay]`, followed by the stretch
+/// exponent `n` for `Envelope::Stretched`.
+#[derive(Clone, Copy, Debug)]
+pub struct DampedRabi {
+    pub envelope: Envelope,
+    pub strategy: GuessStrategy,
+}
+
+impl FitModel for DampedRabi {
+    fn name(&self) -> String {
+        format!("rabi_{}", self.envelope.name())
+    }
+
+    fn param_info(&self) -> Vec<(String, ParamUnit)> {
+        let mut params = Rabi {
+            strategy: self.strategy,
+        }
+        .param_info();
+        params.extend(named(&[("t_decay", ParamUnit::Axis)]));
+        if self.envelope == Envelope::Stretched {
+            params.extend(named(&[("n", ParamUnit::None)]));
+        }
+        params
+    }
+
+    fn n_params(&self) -> usize {
+        self.envelope.n_params()
+    }
+
+    fn evaluate(&self, t: f64, p: &DVector<f64>) -> f64 {
+        let o = p[0];
+        let a = p[1];
+        let tau = p[2];
+        let phi = p[3];
+        let t_decay = p[4];
+        let n = self.envelope.exponent(p);
+        let cos_arg = PI / tau * t + phi;
+        a * (-(t.abs() / t_decay).powf(n)).exp() * cos_arg.cos() + o
+    }
+
+    fn gradient(&self, t: f64, p: &DVector<f64>) -> Vec<f64> {
+        let a = p[1];
+        let tau = p[2];
+        let phi = p[3];
+        let t_decay = p[4];
+        let n = self.envelope.exponent(p);
+
+        let arg = PI / tau * t + phi;
+        let ratio_n = (t.abs() / t_decay).powf(n);
+        let decay = (-ratio_n).exp();
+
+        let df_da = decay * arg.cos();
+        let df_dtau = PI / tau.powi(2) * t * a * decay * arg.sin();
+        let df_dphi = -a * decay * arg.sin();
+        let df_dt_decay = a * arg.cos() * decay * n * ratio_n / t_decay;
+        let mut gradient = vec![1.0, df_da, df_dtau, df_dphi, df_dt_decay];
+        if self.envelope == Envelope::Stretched {
+            // (|t| / t_decay)^n * ln(|t| / t_decay) -> 0 for t -> 0
+            let df_dn = if t == 0.0 {
+                0.0
+            } else {
+                -a * arg.cos() * decay * ratio_n * (t.abs() / t_decay).ln()
+            };
+            gradient.push(df_dn);
+        }
+        gradient
+    }
+
+    fn bounds(&self) -> Vec<(f64, f64)> {
+        let mut bounds = vec![FREE, FREE, NON_NEGATIVE, FREE, NON_NEGATIVE];
+        if self.envelope == Envelope::Stretched {
+            bounds.push(NON_NEGATIVE);
+        }
+        bounds
+    }
+
+    fn initial_guess(&self, x_data: &Array1<f64>, y_data: &Array1<f64>) -> Vec<f64> {
+        let mut init_param = initial_guess(x_data, y_data, self.strategy);
+        // Start with a decay time as long as the measured trace.
+        init_param.push(x_data[x_data.len() - 1] - x_data[0]);
+        if self.envelope == Envelope::Stretched {
+            init_param.push(1.0);
+        }
+        init_param
     }
 }
 
-impl DataContainer {
-    pub fn fit_rabi_image(&self) -> Array3<f64> {
-        let zdim = self.data.shape()[2];
-        let x_axis = Array::linspace(0.0, 1.0, zdim);
-        let dims: Vec<usize> = self.data.shape().iter().cloned().skip(3).collect();
-        match dims.len() {
-            2 => {
-                let xdim = dims[0];
-                let ydim = dims[1];
-                let re: Array3<f64> = Array3::zeros((xdim, ydim, 4));
-                let re_mutex = Mutex::new(re);
-                (0..xdim).into_par_iter().for_each(|i| {
-                    for j in 0..ydim {
-                        let res = fit(
-                            x_axis.clone(),
-                            self.data.slice(s![0, 0, .., i, j]).to_owned(),
-                        );
-                        let mut re = re_mutex.lock().unwrap();
-                        match res {
-                            Some(result) => re.slice_mut(s![i, j, ..]).assign(&result),
-                            None => {
-                                re.slice_mut(s![i, j, ..])
-                                    .assign(&array![0.0, 0.0, 0.0, 0.0]);
-                                // println!("The optmization failed at {}, {}! Assigning default zero values!", i, j);
-                            }
-                        }
-                    }
-                });
-                let res = re_mutex.into_inner().unwrap();
-                res
-            }
-            1 => {
-                let xdim = dims[0];
-                let re: Array3<f64> = Array3::zeros((xdim, 1, 4));
-                let re_mutex = Mutex::new(re);
-                (0..xdim).into_par_iter().for_each(|i| {
-                    let res = fit(x_axis.clone(), self.data.slice(s![0, 0, .., i]).to_owned());
-                    let mut re = re_mutex.lock().unwrap();
-                    match res {
-                        Some(result) => re.slice_mut(s![i, 0, ..]).assign(&result),
-                        None => {
-                            re.slice_mut(s![i, 0, ..])
-                                .assign(&array![0.0, 0.0, 0.0, 0.0]);
-                            println!("The optmization failed! Assigning default zero values!");
-                        }
-                    }
-                });
-                let res = re_mutex.into_inner().unwrap();
-                res
-            }
-            _ => panic!("For the size of the input array there are no known fitting methos"),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fit_loss::RobustLoss;
+    use crate::fit_model::{assert_fit_recovers, assert_gradient_matches, fit_trace, Constraints};
+
+    #[test]
+    fn test_gradients_match_finite_differences() {
+        let x = Array1::linspace(0.0, 1.0, 51);
+        let strategy = GuessStrategy::Fft;
+        assert_gradient_matches(&Rabi { strategy }, &x, &[1.0, 0.05, 0.2, 0.1]);
+        for (envelope, p) in [
+            (Envelope::Exponential, vec![1.0, 0.05, 0.2, 0.1, 0.5]),
+            (Envelope::Gaussian, vec![1.0, 0.05, 0.2, 0.1, 0.5]),
+            (Envelope::Stretched, vec![1.0, 0.05, 0.2, 0.1, 0.5, 1.4]),
+        ] {
+            assert_gradient_matches(&DampedRabi { envelope, strategy }, &x, &p);
+        }
+    }
+
+    #[test]
+    fn test_fft_guess_handles_several_periods() {
+        let x = Array1::linspace(0.0, 200.0, 101);
+        let model = Rabi {
+            strategy: GuessStrategy::Fft,
+        };
+        let truth = [1.0, 0.05, 12.0, 0.7];
+        let y = x.mapv(|t| model.evaluate(t, &DVector::from_vec(truth.to_vec())));
+        let guess = model.initial_guess(&x, &y);
+        assert!((guess[2] - 12.0).abs() < 1.0);
+        assert_fit_recovers(&model, &x, &truth);
+    }
+
+    #[test]
+    fn test_fft_guess_handles_less_than_one_period() {
+        let x = Array1::linspace(0.0, 1.0, 51);
+        let model = Rabi {
+            strategy: GuessStrategy::Fft,
+        };
+        // Half a period is 0.8, so the trace covers less than one period.
+        let truth = [1.0, 0.05, 0.8, 0.3];
+        let y = x.mapv(|t| model.evaluate(t, &DVector::from_vec(truth.to_vec())));
+        let guess = model.initial_guess(&x, &y);
+        assert!(guess.iter().all(|p| p.is_finite()));
+        // The guess is limited to one period over the sweep.
+        assert!((guess[2] - 0.5).abs() < 1e-12);
+        assert_fit_recovers(&model, &x, &truth);
+    }
+
+    #[test]
+    fn test_fft_guess_handles_descending_axis() {
+        let x = Array1::linspace(200.0, 0.0, 101);
+        let model = Rabi {
+            strategy: GuessStrategy::Fft,
+        };
+        let truth = [1.0, 0.05, 12.0, 0.7];
+        let y = x.mapv(|t| model.evaluate(t, &DVector::from_vec(truth.to_vec())));
+        let guess = model.initial_guess(&x, &y);
+        assert!((guess[2] - 12.0).abs() < 1.0);
+        assert_fit_recovers(&model, &x, &truth);
+    }
+
+    #[test]
+    fn test_fft_guess_is_finite_without_axis_extent() {
+        let model = Rabi {
+            strategy: GuessStrategy::Fft,
+        };
+        for n in [1, 5] {
+            let x = Array1::from_elem(n, 3.0);
+            let y = Array1::linspace(1.0, 2.0, n);
+            let guess = model.initial_guess(&x, &y);
+            assert!(guess.iter().all(|p| p.is_finite()));
+            assert!(guess[2] > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_damped_fit_recovers_decay_time() {
+        let x = Array1::linspace(0.0, 1.0, 101);
+        let model = DampedRabi {
+            envelope: Envelope::Exponential,
+            strategy: GuessStrategy::Fft,
+        };
+        assert_fit_recovers(&model, &x, &[1.0, 0.05, 0.12, 0.0, 0.4]);
+    }
+
+    #[test]
+    fn test_stretched_envelope_handles_negative_times() {
+        // The sweep starts before the pulse, e.g. because of a trigger delay.
+        let x = Array1::linspace(-0.2, 1.0, 121);
+        let model = DampedRabi {
+            envelope: Envelope::Stretched,
+            strategy: GuessStrategy::Fft,
+        };
+        let truth = [1.0, 0.05, 0.12, 1.0, 0.6, 1.5];
+        let y = x.mapv(|t| model.evaluate(t, &DVector::from_vec(truth.to_vec())));
+        assert!(y.iter().all(|y| y.is_finite()));
+        assert_gradient_matches(&model, &x, &truth);
+        let result = fit_trace(
+            &model,
+            &Constraints::new(&model),
+            &RobustLoss::default(),
+            &x,
+            &y,
+            None,
+        );
+        assert!(result.status.is_success());
+        // The phase is only determined up to full turns.
+        let phi = (result.params[3] - truth[3]).rem_euclid(2.0 * PI);
+        assert!(phi.min(2.0 * PI - phi) < 1e-6);
+        for k in [0, 1, 2, 4, 5] {
+            assert!((result.params[k] - truth[k]).abs() < 1e-6);
         }
     }
 }
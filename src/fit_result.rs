@@ -0,0 +1,159 @@
+use crate::fit_common::ImageFit;
+use crate::metadata::Metadata;
+use crate::pipeline::Pipeline;
+#[cfg(feature = "python")]
+use pyo3::prelude::*;
+use std::fmt;
+
+/// Physical dimension of a fit parameter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParamUnit {
+    /// Contrast, counts ratio or another dimensionless number.
+    None,
+    /// Same unit as the sweep axis, e.g. a linewidth or a decay time.
+    Axis,
+    Radian,
+}
+
+impl ParamUnit {
+    fn label(&self, axis_unit: Option<&str>) -> String {
+        match self {
+            ParamUnit::None => String::new(),
+            ParamUnit::Axis => axis_unit.unwrap_or_default().to_string(),
+            ParamUnit::Radian => "rad".to_string(),
+        }
+    }
+}
+
+/// Converts a static parameter list into the form taken by [`FitResult::new`].
+pub fn named(params: &[(&str, ParamUnit)]) -> Vec<(String, ParamUnit)> {
+    params
+        .iter()
+        .map(|(name, unit)| (name.to_string(), *unit))
+        .collect()
+}
+
+/// Result of an image fit: one map per fit parameter, their standard
+/// errors and the per-pixel diagnostics.
+#[cfg_attr(feature = "python", pyclass)]
+#[derive(Clone, Debug)]
+pub struct FitResult {
+    pub model: String,
+    pub param_names: Vec<String>,
+    pub units: Vec<String>,
+    /// Unit of the sweep axis, if known.
+    pub axis_unit: Option<String>,
+    pub fit: ImageFit,
+    /// Processing steps that led to this result, including the fit itself
+    /// if it was run as part of a pipeline.
+    pub history: Pipeline,
+    /// QuPyt metadata of the fitted measurement, if known.
+    pub metadata: Option<Metadata>,
+}
+
+impl FitResult {
+    /// `params` lists name and unit of every parameter in the order of the
+    /// last axis of the parameter maps. `axis_unit` is the unit of the sweep
+    /// axis; without it the parameters measured along the axis get no unit,
+    /// see [`crate::DataContainer::fit`].
+    pub fn new(
+        model: &str,
+        params: Vec<(String, ParamUnit)>,
+        axis_unit: Option<&str>,
+        fit: ImageFit,
+    ) -> Self {
+        FitResult {
+            model: model.to_string(),
+            units: params
+                .iter()
+                .map(|(_, unit)| unit.label(axis_unit))
+                .collect(),
+            param_names: params.into_iter().map(|(name, _)| name).collect(),
+            axis_unit: axis_unit.map(String::from),
+            fit,
+            history: Pipeline::default(),
+            metadata: None,
+        }
+    }
+
+    /// Position of the parameter called `name` along the last axis of
+    /// the parameter maps.
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.param_names
+            .iter()
+            .position(|candidate| candidate == name)
+    }
+}
+
+impl fmt::Display for FitResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (xdim, ydim, _) = self.fit.params.dim();
+        let converged = self.fit.success_mask().iter().filter(|&&ok| ok).count();
+        write!(
+            f,
+            "FitResult(model={:?}, params={:?}, shape=({}, {}), converged={}/{})",
+            self.model,
+            self.param_names,
+            xdim,
+            ydim,
+            converged,
+            xdim * ydim
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fit_esr_nalgebra::EsrModel;
+    use crate::fit_model::FitModel;
+    use crate::fit_rabi_nalgebra::{GuessStrategy, Rabi};
+    use crate::load::{DataContainer, FitOptions};
+    use ndarray::{Array1, Array2, Array3, Array5};
+
+    #[test]
+    fn test_units_follow_axis_unit() {
+        let model = Rabi {
+            strategy: GuessStrategy::Fft,
+        };
+        let result = |axis_unit| {
+            FitResult::new(
+                &model.name(),
+                model.param_info(),
+                axis_unit,
+                ImageFit {
+                    x_axis: Array1::zeros(0),
+                    params: Array3::zeros((0, 0, 4)),
+                    errors: Array3::zeros((0, 0, 4)),
+                    status: Array2::zeros((0, 0)),
+                    n_evaluations: Array2::zeros((0, 0)),
+                    reduced_chi2: Array2::zeros((0, 0)),
+                    r_squared: Array2::zeros((0, 0)),
+                    rms: Array2::zeros((0, 0)),
+                },
+            )
+        };
+        assert_eq!(result(Some("us")).units, ["", "", "us", "rad"]);
+        assert_eq!(result(None).units, ["", "", "", "rad"]);
+        assert_eq!(result(None).param_names[2], "tau");
+    }
+
+    #[test]
+    fn test_units_follow_sweep_axis() {
+        let mut container = DataContainer::from_data(Array5::ones((1, 21, 1, 1, 1)).into_dyn());
+        let units = |container: &DataContainer, axis_unit| {
+            container
+                .esr_fit(EsrModel::Lorentzian, axis_unit, &FitOptions::default())
+                .unwrap()
+                .units
+        };
+        // Without coordinates the sweep is measured in fractions.
+        assert_eq!(units(&container, None), ["", "", ""]);
+        let x = Array1::linspace(2860.0, 2880.0, 21);
+        container.set_axis(1, x.clone(), None).unwrap();
+        assert_eq!(units(&container, None), ["a.u.", "a.u.", "a.u."]);
+        container.set_axis(1, x, Some("MHz")).unwrap();
+        assert_eq!(units(&container, None), ["MHz", "MHz", "MHz"]);
+        assert_eq!(units(&container, Some("GHz")), ["GHz", "GHz", "GHz"]);
+    }
+}
@@ -1,33 +1,32 @@
-use load::DataContainer;
-use numpy::PyArrayDyn;
-use pyo3::prelude::*;
-use pyo3::wrap_pyfunction;
-mod fft;
-mod fit_esr_nalgebra;
-mod fit_rabi_nalgebra;
-mod load;
-mod medfilt;
-use numpy::IntoPyArray;
+//! Pixel-wise fitting of widefield NV magnetometry measurements recorded
+//! with QuPyt.
+//!
+//! The core is plain Rust: load data with [`DataContainer::load`], preprocess
+//! it and fit it with [`DataContainer::fit`] or one of the model specific
+//! methods. The Python bindings live behind the optional `python` feature,
+//! which maturin enables through `pyproject.toml`.
+pub mod error;
+pub mod fft;
+pub mod fit_common;
+pub mod fit_esr_nalgebra;
+pub mod fit_global;
+pub mod fit_hyperfine_nalgebra;
+pub mod fit_loss;
+pub mod fit_model;
+pub mod fit_multi_esr_nalgebra;
+pub mod fit_rabi_nalgebra;
+pub mod fit_result;
+pub mod fit_t1_nalgebra;
+pub mod load;
+pub mod medfilt;
+pub mod metadata;
+pub mod pipeline;
+#[cfg(feature = "python")]
+mod python;
+pub mod save;
 
-#[pymodule]
-fn qufit(_py: Python, m: &PyModule) -> PyResult<()> {
-    m.add_class::<DataContainer>()?;
-    m.add_function(wrap_pyfunction!(medfilt_pyth, m)?)?;
-    Ok(())
-}
-
-#[pyfunction]
-fn medfilt_pyth<'py>(
-    py: Python<'py>,
-    input: &PyArrayDyn<f64>,
-    kernel_size: usize,
-) -> &'py PyArrayDyn<f64> {
-    let input_array = unsafe {
-        input
-            .as_array()
-            .into_dimensionality::<ndarray::Ix3>()
-            .expect("Input needs to be a 3D array")
-    };
-    let result = medfilt::medfilt_rust(&input_array.view(), kernel_size);
-    result.into_dyn().into_pyarray(py)
-}
+pub use error::QufitError;
+pub use fit_result::FitResult;
+pub use load::{DataContainer, FitOptions, Sigma};
+pub use metadata::Metadata;
+pub use pipeline::Pipeline;
@@ -1,105 +1,649 @@
-use ndarray::{s, Array, ArrayD, Axis, Dimension, IxDyn, Slice};
-use ndarray_npy::read_npy;
-use numpy::IntoPyArray;
-use pyo3::exceptions::PyValueError;
+use crate::error::QufitError;
+use crate::fit_esr_nalgebra::EsrModel;
+use crate::fit_loss::RobustLoss;
+use crate::fit_model::{param_index, Constraints, FitModel, FitSettings, NamedBounds};
+use crate::fit_multi_esr_nalgebra::MultiLorentzian;
+use crate::fit_rabi_nalgebra::{DampedRabi, Envelope, GuessStrategy, Rabi};
+use crate::fit_result::FitResult;
+use crate::fit_t1_nalgebra::StretchedExponential;
+use crate::metadata::{Metadata, Sweep};
+use crate::pipeline::{Pipeline, Step};
+use ndarray::{s, Array, Array1, ArrayD, ArrayViewD, Axis, Dimension, IxDyn, Slice, Zip};
+use ndarray_npy::{read_npy, ReadNpyError, ReadableElement};
+#[cfg(feature = "python")]
 use pyo3::prelude::*;
 use std::cmp::min;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Reads `path` as an array of `T` and converts it to f64. Returns `None`
+/// if the dtype stored in the file header is not `T`.
+fn read_npy_converted<T: ReadableElement + Clone>(
+    path: &str,
+    convert: fn(T) -> f64,
+) -> Option<Result<ArrayD<f64>, ReadNpyError>> {
+    match read_npy::<_, ArrayD<T>>(path) {
+        Ok(data) => Some(Ok(data.mapv(convert))),
+        Err(ReadNpyError::WrongDescriptor(_)) => None,
+        Err(e) => Some(Err(e)),
+    }
+}
+
+/// Standard deviation of the data points, used to weight a fit. It is taken
+/// as absolute: the parameter errors are not rescaled by the reduced
+/// chi-square, see [`crate::fit_common::standard_errors`].
+#[derive(Clone, Debug)]
+pub enum Sigma {
+    /// The uncertainty propagated from the raw counts by
+    /// [`DataContainer::reference_ratio`] or [`DataContainer::reference_sum`].
+    ShotNoise,
+    /// One value per data point, with the shape of the data.
+    Values(ArrayD<f64>),
+}
+
+/// Settings shared by all fits of a [`DataContainer`]. Parameters are
+/// referred to by the names of [`FitModel::param_info`].
+#[derive(Clone, Debug, Default)]
+pub struct FitOptions {
+    /// `(lower, upper)` per parameter, with `None` for an open side. They
+    /// narrow the bounds of the model, e.g. widths stay non-negative.
+    pub bounds: NamedBounds,
+    /// Parameters held at the given value.
+    pub fixed: HashMap<String, f64>,
+    pub sigma: Option<Sigma>,
+    pub loss: RobustLoss,
+    /// Blocks of `coarse_bin` x `coarse_bin` pixels are averaged and fitted
+    /// first, and every pixel starts from the result of its block.
+    pub coarse_bin: Option<usize>,
+    /// Parameters common to the whole field of view.
+    pub shared: Vec<String>,
+}
 
 #[derive(Debug)]
-#[pyclass]
+#[cfg_attr(feature = "python", pyclass)]
 pub struct DataContainer {
     pub data: Array<f64, IxDyn>,
+    /// Physical coordinates for each axis of `data`. `None` means the
+    /// axis has no known coordinates and falls back to `linspace(0, 1)`.
+    pub axes: Vec<Option<Array1<f64>>>,
+    /// Unit of the coordinates in `axes`, e.g. `Hz` for a frequency sweep
+    /// from the metadata, if known.
+    pub axis_units: Vec<Option<String>>,
+    pub dim_names: Vec<String>,
+    pub metadata: Option<Metadata>,
+    /// Standard deviation of every point of `data`, if known. Set by the
+    /// reference operations from the counting statistics of the raw data.
+    pub sigma: Option<ArrayD<f64>>,
+    /// Processing steps applied since the data was loaded.
+    pub history: Pipeline,
 }
 
-#[pymethods]
 impl DataContainer {
-    #[new]
-    pub fn new(path: String) -> Self {
+    /// Loads a .npy file of any supported numeric dtype, together with the
+    /// axis coordinates in `<stem>_axis<dim>.npy` files and the metadata
+    /// sidecar QuPyt writes next to the data, if present. Folders and .npz
+    /// files are read with [`DataContainer::load_saved`].
+    ///
+    /// Every sweep in the metadata is matched to a sweep dimension (1 or 2)
+    /// of the data: the one it declares, or else the only one whose length
+    /// equals the number of dynamic steps. Loading fails if that is
+    /// ambiguous or two sweeps claim the same dimension.
+    pub fn load(path: &str) -> Result<Self, QufitError> {
+        if path.ends_with(".npz") || Path::new(path).is_dir() {
+            return Self::load_saved(Path::new(path));
+        }
+        let data = Self::load_data(path)?;
+        let axes = Self::load_axes(path, data.shape())?;
+        let metadata = match Metadata::find_sidecar(path) {
+            Some(sidecar) => Some(Metadata::load(&sidecar)?),
+            None => None,
+        };
+        let mut container = Self {
+            dim_names: Self::default_dim_names(data.ndim()),
+            axis_units: vec![None; data.ndim()],
+            data,
+            axes,
+            metadata,
+            sigma: None,
+            history: Pipeline::default(),
+        };
+        container.apply_metadata()?;
+        Ok(container)
+    }
+
+    /// Wraps `data` without axis coordinates or metadata.
+    pub fn from_data(data: ArrayD<f64>) -> Self {
         Self {
-            data: Self::load_data(path),
+            dim_names: Self::default_dim_names(data.ndim()),
+            axes: vec![None; data.ndim()],
+            axis_units: vec![None; data.ndim()],
+            data,
+            metadata: None,
+            sigma: None,
+            history: Pipeline::default(),
         }
     }
 
-    pub fn reference_ratio(&mut self) -> PyResult<()> {
-        if self.data.len_of(Axis(0)) != 2 {
-            return Err(PyValueError::new_err(
-                "The first axis should have a size of 2 for division",
-            ));
+    /// Replaces the data in place. Axis coordinates are kept for all axes
+    /// whose length did not change. The history is cleared.
+    pub fn set_data(&mut self, data: ArrayD<f64>) {
+        if data.ndim() != self.data.ndim() {
+            self.axes = vec![None; data.ndim()];
+            self.axis_units = vec![None; data.ndim()];
+            self.dim_names = Self::default_dim_names(data.ndim());
+        } else {
+            for (dim, axis) in self.axes.iter_mut().enumerate() {
+                if axis
+                    .as_ref()
+                    .is_some_and(|a| a.len() != data.len_of(Axis(dim)))
+                {
+                    *axis = None;
+                    self.axis_units[dim] = None;
+                }
+            }
         }
-        let a0 = self.data.slice_axis(Axis(0), Slice::new(0, Some(1), 1));
-        let a1 = self.data.slice_axis(Axis(0), Slice::new(1, Some(2), 1));
-        self.data = (&a0 / &a1).to_owned();
+        self.data = data;
+        self.sigma = None;
+        self.history = Pipeline::default();
+    }
+
+    /// Sets the standard deviation of every data point, or clears it with
+    /// `None`. Used by the fits with [`Sigma::ShotNoise`].
+    pub fn set_sigma(&mut self, sigma: Option<ArrayD<f64>>) -> Result<(), QufitError> {
+        self.sigma = sigma.map(|sigma| self.checked_sigma(sigma)).transpose()?;
         Ok(())
     }
 
-    pub fn reference_sum(&mut self) -> PyResult<()> {
-        if self.data.len_of(Axis(0)) != 2 {
-            return Err(PyValueError::new_err(
-                "The first axis should have a size of 2 for division",
-            ));
+    /// Coordinates along `dim`, see [`DataContainer::sweep_axis`].
+    pub fn axis(&self, dim: usize) -> Result<Array1<f64>, QufitError> {
+        self.sweep_axis(dim)
+    }
+
+    /// Unit of the coordinates along `dim`, if known.
+    pub fn axis_unit(&self, dim: usize) -> Option<&str> {
+        self.axis_units.get(dim)?.as_deref()
+    }
+
+    /// Sets the coordinates along `dim` and their `unit`, if known. The
+    /// coordinates need to be finite.
+    pub fn set_axis(
+        &mut self,
+        dim: usize,
+        values: Array1<f64>,
+        unit: Option<&str>,
+    ) -> Result<(), QufitError> {
+        if dim >= self.data.ndim() {
+            return Err(QufitError::Value(format!(
+                "The data only has {} dimensions, cannot set axis {}",
+                self.data.ndim(),
+                dim
+            )));
         }
-        let a0 = self.data.slice_axis(Axis(0), Slice::new(0, Some(1), 1));
-        let a1 = self.data.slice_axis(Axis(0), Slice::new(1, Some(2), 1));
-        self.data = ((&a0 - &a1) / (&a0 + &a1)).to_owned();
+        if values.len() != self.data.len_of(Axis(dim)) {
+            return Err(QufitError::Shape(format!(
+                "Axis {} has {} points, but {} coordinates were given",
+                dim,
+                self.data.len_of(Axis(dim)),
+                values.len()
+            )));
+        }
+        check_coordinates(&values, &format!("Axis {}", dim))?;
+        self.axes[dim] = Some(values);
+        self.axis_units[dim] = unit.map(String::from);
         Ok(())
     }
 
-    pub fn compress_data(&mut self, stepsize: usize) {
-        self.data = match self.data.ndim() {
-            4 => {
-                if self.data.shape().last().unwrap() == &1 {
-                    self.data.clone()
-                } else {
-                    self.blockwise_mean_1d(stepsize)
-                }
+    /// Divides the signal by the reference along the first axis.
+    pub fn reference_ratio(&mut self) -> Result<(), QufitError> {
+        // a / b: var = var_a / b^2 + a^2 var_b / b^4
+        self.combine_reference(
+            |a, b| a / b,
+            |a, b, va, vb| va / b.powi(2) + a.powi(2) * vb / b.powi(4),
+        )?;
+        self.history.steps.push(Step::ReferenceRatio);
+        Ok(())
+    }
+
+    /// Contrast `(signal - reference) / (signal + reference)` along the
+    /// first axis.
+    pub fn reference_sum(&mut self) -> Result<(), QufitError> {
+        // (a - b) / (a + b): var = 4 (b^2 var_a + a^2 var_b) / (a + b)^4
+        self.combine_reference(
+            |a, b| (a - b) / (a + b),
+            |a, b, va, vb| 4.0 * (b.powi(2) * va + a.powi(2) * vb) / (a + b).powi(4),
+        )?;
+        self.history.steps.push(Step::ReferenceSum);
+        Ok(())
+    }
+
+    /// Averages blocks of `stepsize` pixels. A known `sigma` is propagated
+    /// to the standard deviation of the block means.
+    pub fn compress_data(&mut self, stepsize: usize) -> Result<(), QufitError> {
+        *self = self.binned(stepsize, self.sigma.as_ref())?;
+        self.history.steps.push(Step::CompressData { stepsize });
+        Ok(())
+    }
+
+    /// Fits an ESR lineshape along the first sweep axis. `axis_unit`
+    /// overrides the unit of the axis, which otherwise comes from the
+    /// metadata or [`DataContainer::set_axis`] if the axis has coordinates.
+    /// The hyperfine models fail if the unit is not known, see
+    /// [`EsrModel::fit_model`].
+    pub fn esr_fit(
+        &self,
+        model: EsrModel,
+        axis_unit: Option<&str>,
+        options: &FitOptions,
+    ) -> Result<FitResult, QufitError> {
+        let axis_unit = axis_unit.or(self.coordinate_unit(1));
+        let fit_model = model.fit_model(axis_unit)?;
+        self.fit(fit_model.as_ref(), 1, axis_unit, options)
+    }
+
+    /// Fits `n_peaks` Lorentzian dips along the first sweep axis.
+    pub fn esr_multi_fit(
+        &self,
+        n_peaks: usize,
+        shared_width: bool,
+        axis_unit: Option<&str>,
+        options: &FitOptions,
+    ) -> Result<FitResult, QufitError> {
+        if n_peaks == 0 {
+            return Err(QufitError::Value(
+                "n_peaks needs to be at least 1".to_string(),
+            ));
+        }
+        let model = MultiLorentzian {
+            n_peaks,
+            shared_width,
+        };
+        self.fit(&model, 1, axis_unit, options)
+    }
+
+    /// Fits a Rabi oscillation along the second sweep axis, undamped or
+    /// with the decay `damping`.
+    pub fn rabi_fit(
+        &self,
+        damping: Option<Envelope>,
+        strategy: GuessStrategy,
+        axis_unit: Option<&str>,
+        options: &FitOptions,
+    ) -> Result<FitResult, QufitError> {
+        match damping {
+            Some(envelope) => self.fit(&DampedRabi { envelope, strategy }, 2, axis_unit, options),
+            None => self.fit(&Rabi { strategy }, 2, axis_unit, options),
+        }
+    }
+
+    /// Fits a stretched exponential decay along the second sweep axis.
+    pub fn t1_fit(
+        &self,
+        axis_unit: Option<&str>,
+        options: &FitOptions,
+    ) -> Result<FitResult, QufitError> {
+        self.fit(&StretchedExponential, 2, axis_unit, options)
+    }
+}
+
+impl DataContainer {
+    /// Reads a .npy file of any supported numeric dtype into an f64 array.
+    fn load_data(path: &str) -> Result<Array<f64, IxDyn>, QufitError> {
+        let data = read_npy_converted::<u8>(path, |x| x as f64)
+            .or_else(|| read_npy_converted::<u16>(path, |x| x as f64))
+            .or_else(|| read_npy_converted::<u32>(path, |x| x as f64))
+            .or_else(|| read_npy_converted::<u64>(path, |x| x as f64))
+            .or_else(|| read_npy_converted::<i32>(path, |x| x as f64))
+            .or_else(|| read_npy_converted::<i64>(path, |x| x as f64))
+            .or_else(|| read_npy_converted::<f32>(path, |x| x as f64))
+            .or_else(|| read_npy_converted::<f64>(path, |x| x));
+        match data {
+            Some(result) => {
+                result.map_err(|e| QufitError::Io(format!("Could not read {}: {}", path, e)))
+            }
+            None => Err(QufitError::Dtype(format!(
+                "{} has an unsupported dtype. Supported are uint8, uint16, uint32, uint64, int32, int64, float32 and float64",
+                path
+            ))),
+        }
+    }
+
+    /// Looks for `<stem>_axis<dim>.npy` files next to the data file and
+    /// loads them as the coordinates of the respective axis.
+    fn load_axes(path: &str, shape: &[usize]) -> Result<Vec<Option<Array1<f64>>>, QufitError> {
+        let path = Path::new(path);
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let mut axes = vec![None; shape.len()];
+        for (dim, axis) in axes.iter_mut().enumerate() {
+            let axis_path = path.with_file_name(format!("{}_axis{}.npy", stem, dim));
+            if !axis_path.exists() {
+                continue;
             }
-            5 => self.blockwise_mean_2d(stepsize),
-            _ => {
-                panic!("The array has an unsupported number of dimensions. There is no method defined for this case. {:?}", self.data.shape())
+            let values: Array1<f64> = read_npy(&axis_path).map_err(|e| {
+                QufitError::Io(format!("Could not read {}: {}", axis_path.display(), e))
+            })?;
+            if values.len() != shape[dim] {
+                return Err(QufitError::Shape(format!(
+                    "{} has {} entries, but axis {} of the data has {} points",
+                    axis_path.display(),
+                    values.len(),
+                    dim,
+                    shape[dim]
+                )));
             }
+            check_coordinates(&values, &axis_path.display().to_string())?;
+            *axis = Some(values);
         }
+        Ok(axes)
     }
 
-    pub fn esr_fit(&self, py: Python<'_>) -> PyResult<PyObject> {
-        let out = self.fit_esr_image();
-        Ok(out.into_pyarray(py).to_object(py))
+    /// Fits `model` to the trace along `sweep_dim` of every pixel.
+    /// `axis_unit` overrides the unit of the sweep axis. Otherwise the
+    /// parameters measured along the axis take the unit of its coordinates,
+    /// `a.u.` if that is not known, and none if the axis has no coordinates
+    /// and they are fractions of the sweep. The result records the
+    /// processing history and the metadata of the data. `sweep_dim` is 1
+    /// or 2, other dimensions are a `Value` error.
+    pub fn fit(
+        &self,
+        model: &dyn FitModel,
+        sweep_dim: usize,
+        axis_unit: Option<&str>,
+        options: &FitOptions,
+    ) -> Result<FitResult, QufitError> {
+        let constraints = Constraints::from_names(model, &options.bounds, &options.fixed)?;
+        let axis_unit = axis_unit.or_else(|| match self.axes.get(sweep_dim) {
+            Some(Some(_)) => Some(self.coordinate_unit(sweep_dim).unwrap_or("a.u.")),
+            _ => None,
+        });
+        let loss = options.loss;
+        if loss
+            .f_scale
+            .is_some_and(|f_scale| f_scale <= 0.0 || !f_scale.is_finite())
+        {
+            return Err(QufitError::Value(
+                "f_scale needs to be positive and finite".to_string(),
+            ));
+        }
+        let sigma = match &options.sigma {
+            None => None,
+            Some(Sigma::ShotNoise) => Some(self.sigma.clone().ok_or_else(|| {
+                QufitError::Value(
+                    "No shot noise is known, call reference_ratio or reference_sum on the raw counts first"
+                        .to_string(),
+                )
+            })?),
+            Some(Sigma::Values(sigma)) => Some(self.checked_sigma(sigma.clone())?),
+        };
+        if let Some(coarse_bin) = options.coarse_bin {
+            if coarse_bin == 0 {
+                return Err(QufitError::Value(
+                    "coarse_bin needs to be at least 1".to_string(),
+                ));
+            }
+            if !matches!(self.data.ndim(), 4 | 5) {
+                return Err(QufitError::Shape(format!(
+                    "coarse_bin needs pixel axes, but the data has shape {:?}",
+                    self.data.shape()
+                )));
+            }
+        }
+        let shared = options
+            .shared
+            .iter()
+            .map(|name| param_index(model, name))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(QufitError::Value)?;
+        if shared.iter().any(|&k| constraints.fixed[k].is_some()) {
+            return Err(QufitError::Value(
+                "A parameter cannot be fixed and shared at the same time".to_string(),
+            ));
+        }
+        if !shared.is_empty() && !loss.is_linear() {
+            return Err(QufitError::Value(
+                "Global fits with shared parameters only support the linear loss".to_string(),
+            ));
+        }
+        let settings = FitSettings {
+            constraints,
+            loss,
+            coarse_bin: options.coarse_bin,
+        };
+        let mut result = if shared.is_empty() {
+            self.fit_model_image(model, &settings, sweep_dim, sigma.as_ref(), axis_unit)
+        } else {
+            self.fit_global_image(
+                model,
+                &settings,
+                &shared,
+                sweep_dim,
+                sigma.as_ref(),
+                axis_unit,
+            )
+        }?;
+        result.history = self.history.clone();
+        result.metadata = self.metadata.clone();
+        Ok(result)
     }
 
-    pub fn rabi_fit(&self, py: Python<'_>) -> PyResult<PyObject> {
-        let out = self.fit_rabi_image();
-        Ok(out.into_pyarray(py).to_object(py))
+    /// Unit of the coordinates along `dim`. A stored unit does not apply
+    /// while the axis has no coordinates and runs over fractions of the
+    /// sweep.
+    fn coordinate_unit(&self, dim: usize) -> Option<&str> {
+        self.axes.get(dim)?.as_ref()?;
+        self.axis_unit(dim)
     }
 
-    pub fn get_data(&self, py: Python<'_>) -> PyResult<PyObject> {
-        let pyarray = self.data.clone().into_pyarray(py).to_object(py);
-        Ok(pyarray.into())
+    /// Checks that `sigma` matches the data and can be used as weights.
+    fn checked_sigma(&self, sigma: ArrayD<f64>) -> Result<ArrayD<f64>, QufitError> {
+        if sigma.shape() != self.data.shape() {
+            return Err(QufitError::Shape(format!(
+                "sigma has shape {:?}, but the data has shape {:?}",
+                sigma.shape(),
+                self.data.shape()
+            )));
+        }
+        if !sigma.iter().all(|s| s.is_finite() && *s > 0.0) {
+            return Err(QufitError::Value(
+                "sigma needs to be positive and finite everywhere".to_string(),
+            ));
+        }
+        Ok(sigma)
     }
 
-    pub fn fft(&self, py: Python<'_>) -> PyResult<PyObject> {
-        let pyarray = self.array_fft();
-        Ok(pyarray.into_pyarray(py).to_object(py))
+    /// Copy of the container with blocks of `stepsize` pixels averaged and
+    /// `sigma` propagated to the standard deviation of the block means.
+    pub(crate) fn binned(
+        &self,
+        stepsize: usize,
+        sigma: Option<&ArrayD<f64>>,
+    ) -> Result<DataContainer, QufitError> {
+        if stepsize == 0 {
+            return Err(QufitError::Value(
+                "stepsize needs to be at least 1".to_string(),
+            ));
+        }
+        let mut axes = self.axes.clone();
+        for axis in axes.iter_mut().skip(3).flatten() {
+            *axis = Self::blockwise_mean_axis(axis, stepsize);
+        }
+        Ok(DataContainer {
+            data: Self::compress(&self.data, stepsize, |block| {
+                block.mean().unwrap_or(f64::NAN)
+            })?,
+            axes,
+            axis_units: self.axis_units.clone(),
+            dim_names: self.dim_names.clone(),
+            metadata: self.metadata.clone(),
+            sigma: sigma
+                .map(|sigma| {
+                    Self::compress(sigma, stepsize, |block| {
+                        block.mapv(|s| s * s).sum().sqrt() / block.len() as f64
+                    })
+                })
+                .transpose()?,
+            history: self.history.clone(),
+        })
     }
 
-    pub fn hilbert(&self, py: Python<'_>) -> PyResult<PyObject> {
-        let pyarray = self.array_hilbert();
-        Ok(pyarray.into_pyarray(py).to_object(py))
+    /// Replaces signal `a` and reference `b` along the first axis by
+    /// `combine(a, b)` and sets `sigma` from the propagated
+    /// `variance(a, b, var_a, var_b)`.
+    fn combine_reference(
+        &mut self,
+        combine: fn(f64, f64) -> f64,
+        variance: fn(f64, f64, f64, f64) -> f64,
+    ) -> Result<(), QufitError> {
+        if self.data.shape().first() != Some(&2) {
+            return Err(QufitError::Shape(format!(
+                "The first axis should have a size of 2 for division, but the data has shape {:?}",
+                self.data.shape()
+            )));
+        }
+        let a0 = self.data.slice_axis(Axis(0), Slice::new(0, Some(1), 1));
+        let a1 = self.data.slice_axis(Axis(0), Slice::new(1, Some(2), 1));
+        let raw_variance = self.raw_variance();
+        let v0 = raw_variance.slice_axis(Axis(0), Slice::new(0, Some(1), 1));
+        let v1 = raw_variance.slice_axis(Axis(0), Slice::new(1, Some(2), 1));
+        let sigma = Zip::from(&a0)
+            .and(&a1)
+            .and(&v0)
+            .and(&v1)
+            .map_collect(|&a, &b, &va, &vb| variance(a, b, va, vb).sqrt());
+        self.data = Zip::from(&a0).and(&a1).map_collect(|&a, &b| combine(a, b));
+        self.sigma = Some(sigma);
+        self.axes[0] = None;
+        self.axis_units[0] = None;
+        Ok(())
     }
 
-    pub fn medfilt(&self, kernel_size: usize, py: Python<'_>) -> PyResult<PyObject> {
-        let out = self.medfilt_array(kernel_size);
-        Ok(out.into_pyarray(py).to_object(py))
+    /// Variance of every raw data point: the known `sigma` squared, or the
+    /// counts themselves (Poisson statistics, at least one count).
+    fn raw_variance(&self) -> ArrayD<f64> {
+        match &self.sigma {
+            Some(sigma) => sigma.mapv(|s| s * s),
+            None => self.data.mapv(|n| n.max(1.0)),
+        }
     }
-}
 
-impl DataContainer {
-    fn load_data(path: String) -> Array<f64, IxDyn> {
-        let data: Array<u32, IxDyn> = read_npy(path).unwrap();
-        let data = data.mapv(|x| x as f64);
-        data
+    fn default_dim_names(ndim: usize) -> Vec<String> {
+        ["reference", "sweep_1", "sweep_2", "x", "y"]
+            .iter()
+            .map(|name| name.to_string())
+            .chain((5..ndim).map(|dim| format!("dim_{}", dim)))
+            .take(ndim)
+            .collect()
     }
 
-    fn get_new_shape(&self, stepsize: usize) -> Vec<usize> {
+    /// Uses the sweeps in the metadata to fill in the coordinates, units
+    /// and names of the sweep axes (1 and 2), see [`DataContainer::load`].
+    /// Coordinates that were already loaded from `_axis<dim>.npy` files are
+    /// kept. Sweeps that match no dimension are left out.
+    fn apply_metadata(&mut self) -> Result<(), QufitError> {
+        let Some(metadata) = &self.metadata else {
+            return Ok(());
+        };
         let shape = self.data.shape().to_vec();
+        let sweep_dims = 1..min(3, shape.len());
+        let mut matched: Vec<Option<&Sweep>> = vec![None; shape.len()];
+        for sweep in &metadata.sweeps {
+            let dim = match sweep.dim {
+                Some(dim) if sweep_dims.contains(&dim) => dim,
+                Some(dim) => {
+                    return Err(QufitError::Shape(format!(
+                        "The sweep {} runs along dimension {}, but the data with shape {:?} only has sweep dimensions {:?}",
+                        sweep.name(),
+                        dim,
+                        shape,
+                        sweep_dims
+                    )))
+                }
+                None => {
+                    let candidates: Vec<usize> = sweep_dims
+                        .clone()
+                        .filter(|&dim| {
+                            shape[dim] > 1
+                                && metadata
+                                    .dynamic_steps
+                                    .is_none_or(|steps| steps == shape[dim])
+                        })
+                        .collect();
+                    match candidates[..] {
+                        [] => continue,
+                        [dim] => dim,
+                        _ => {
+                            return Err(QufitError::Shape(format!(
+                                "The sweep {} could run along any of the dimensions {:?} of the data with shape {:?}. Declare its `dim` in the metadata",
+                                sweep.name(),
+                                candidates,
+                                shape
+                            )))
+                        }
+                    }
+                }
+            };
+            if let Some(other) = matched[dim] {
+                return Err(QufitError::Shape(format!(
+                    "The sweeps {} and {} both run along dimension {}. Declare the `dim` of each in the metadata",
+                    other.name(),
+                    sweep.name(),
+                    dim
+                )));
+            }
+            matched[dim] = Some(sweep);
+        }
+        for (dim, sweep) in matched.into_iter().enumerate() {
+            let Some(sweep) = sweep else {
+                continue;
+            };
+            if self.axes[dim].is_none() {
+                self.axes[dim] = Some(sweep.axis(shape[dim]));
+            }
+            self.axis_units[dim] = sweep.unit.clone();
+            self.dim_names[dim] = sweep.name();
+        }
+        Ok(())
+    }
+
+    /// Coordinates along `dim`. Axes without known coordinates are
+    /// returned as `linspace(0, 1)`, i.e. in fractions of the sweep.
+    pub fn sweep_axis(&self, dim: usize) -> Result<Array1<f64>, QufitError> {
+        match self.axes.get(dim) {
+            Some(Some(axis)) => Ok(axis.clone()),
+            Some(None) => Ok(Array::linspace(0.0, 1.0, self.data.len_of(Axis(dim)))),
+            None => Err(QufitError::Value(format!(
+                "The data only has {} dimensions, there is no axis {}",
+                self.data.ndim(),
+                dim
+            ))),
+        }
+    }
+
+    fn blockwise_mean_axis(axis: &Array1<f64>, stepsize: usize) -> Array1<f64> {
+        axis.to_vec()
+            .chunks(stepsize)
+            .map(|chunk| chunk.iter().sum::<f64>() / chunk.len() as f64)
+            .collect()
+    }
+
+    /// Reduces blocks of `stepsize` pixels along the last one or two axes
+    /// of `array` with `reduce`.
+    fn compress(
+        array: &ArrayD<f64>,
+        stepsize: usize,
+        reduce: fn(ArrayViewD<f64>) -> f64,
+    ) -> Result<ArrayD<f64>, QufitError> {
+        match array.ndim() {
+            4 if array.shape()[3] == 1 => Ok(array.clone()),
+            4 => Ok(Self::blockwise_1d(array, stepsize, reduce)),
+            5 => Ok(Self::blockwise_2d(array, stepsize, reduce)),
+            _ => Err(QufitError::Shape(format!(
+                "Binning needs data with 4 or 5 dimensions, but the data has shape {:?}",
+                array.shape()
+            ))),
+        }
+    }
+
+    fn get_new_shape(shape: &[usize], stepsize: usize) -> Vec<usize> {
         let compressed_shape: Vec<_> = shape
             .iter()
             .skip(3)
@@ -114,11 +658,15 @@ impl DataContainer {
         new_shape
     }
 
-    fn blockwise_mean_2d(&self, stepsize: usize) -> ArrayD<f64> {
-        let shape = self.data.shape();
+    fn blockwise_2d(
+        array: &ArrayD<f64>,
+        stepsize: usize,
+        reduce: fn(ArrayViewD<f64>) -> f64,
+    ) -> ArrayD<f64> {
+        let shape = array.shape();
         let max_index_3 = shape[3];
         let max_index_4 = shape[4];
-        let new_shape = self.get_new_shape(stepsize);
+        let new_shape = Self::get_new_shape(shape, stepsize);
         let mut result: ArrayD<f64> = Array::zeros(new_shape.as_slice());
         for new_idx in result.clone().indexed_iter() {
             let idx = new_idx.0.slice();
@@ -130,16 +678,20 @@ impl DataContainer {
                 stepsize * idx_vec[3]..min(stepsize * (idx_vec[3] + 1), max_index_3),
                 stepsize * idx_vec[4]..min(stepsize * (idx_vec[4] + 1), max_index_4)
             ];
-            let update_value = self.data.slice(slicer);
-            result[idx] = update_value.mean().unwrap();
+            let update_value = array.slice(slicer);
+            result[idx] = reduce(update_value.into_dyn());
         }
         result
     }
 
-    fn blockwise_mean_1d(&self, stepsize: usize) -> ArrayD<f64> {
-        let shape = self.data.shape();
+    fn blockwise_1d(
+        array: &ArrayD<f64>,
+        stepsize: usize,
+        reduce: fn(ArrayViewD<f64>) -> f64,
+    ) -> ArrayD<f64> {
+        let shape = array.shape();
         let max_index_3 = shape[3];
-        let new_shape = self.get_new_shape(stepsize);
+        let new_shape = Self::get_new_shape(shape, stepsize);
         let mut result: ArrayD<f64> = Array::zeros(new_shape.as_slice());
         for new_idx in result.clone().indexed_iter() {
             let idx = new_idx.0.slice();
@@ -150,9 +702,194 @@ impl DataContainer {
                 idx_vec[2],
                 stepsize * idx_vec[3]..min(stepsize * (idx_vec[3] + 1), max_index_3)
             ];
-            let update_value = self.data.slice(slicer);
-            result[idx] = update_value.mean().unwrap();
+            let update_value = array.slice(slicer);
+            result[idx] = reduce(update_value.into_dyn());
         }
         result
     }
 }
+
+/// Fails if `values`, the coordinates of `what`, contain NaN or infinity.
+fn check_coordinates(values: &Array1<f64>, what: &str) -> Result<(), QufitError> {
+    if values.iter().all(|v| v.is_finite()) {
+        return Ok(());
+    }
+    Err(QufitError::Value(format!(
+        "{} has coordinates that are NaN or infinite",
+        what
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fit_hyperfine_nalgebra::Hyperfine;
+    use ndarray::array;
+    use ndarray_npy::write_npy;
+
+    #[test]
+    fn test_read_npy_converted_checks_dtype() {
+        let path = std::env::temp_dir().join("qufit_test_read_u16.npy");
+        write_npy(&path, &array![[1u16, 2], [3, 4]]).unwrap();
+        let path = path.display().to_string();
+
+        assert!(read_npy_converted::<u32>(&path, |x| x as f64).is_none());
+        assert!(read_npy_converted::<f64>(&path, |x| x).is_none());
+        let data = read_npy_converted::<u16>(&path, |x| x as f64)
+            .unwrap()
+            .unwrap();
+        assert_eq!(data, array![[1., 2.], [3., 4.]].into_dyn());
+    }
+
+    #[test]
+    fn test_reference_sum_propagates_shot_noise() {
+        let mut container = DataContainer::from_data(
+            Array::from_shape_vec((2, 1, 1, 2), vec![100.0, 300.0, 50.0, 100.0])
+                .unwrap()
+                .into_dyn(),
+        );
+        container.reference_sum().unwrap();
+        let sigma = container.sigma.clone().unwrap();
+        let expected = |a: f64, b: f64| (4.0 * a * b / (a + b).powi(3)).sqrt();
+        assert!((sigma[[0, 0, 0, 0]] - expected(100.0, 50.0)).abs() < 1e-12);
+        assert!((sigma[[0, 0, 0, 1]] - expected(300.0, 100.0)).abs() < 1e-12);
+
+        container.compress_data(2).unwrap();
+        let compressed = container.sigma.unwrap()[[0, 0, 0, 0]];
+        let combined = (expected(100.0, 50.0).powi(2) + expected(300.0, 100.0).powi(2)).sqrt();
+        assert!((compressed - combined / 2.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_unsupported_shapes_are_errors() {
+        let mut container = DataContainer::from_data(Array::zeros((3, 4, 5)).into_dyn());
+        assert!(matches!(
+            container.binned(2, None),
+            Err(QufitError::Shape(_))
+        ));
+        assert!(matches!(
+            container.combine_reference(|a, b| a / b, |_, _, va, _| va),
+            Err(QufitError::Shape(_))
+        ));
+        assert!(matches!(container.array_fft(), Err(QufitError::Shape(_))));
+        assert!(matches!(
+            container.medfilt_array(3),
+            Err(QufitError::Shape(_))
+        ));
+    }
+
+    #[test]
+    fn test_sweeps_are_matched_to_dimensions() {
+        let with_metadata = |shape: (usize, usize, usize, usize, usize), yaml: &str| {
+            let mut container = DataContainer::from_data(Array::zeros(shape).into_dyn());
+            let raw = serde_yaml::from_str(yaml).unwrap();
+            container.metadata = Some(Metadata::from_value("test.yaml".to_string(), raw));
+            container.apply_metadata().map(|_| container)
+        };
+        // The pair in the pulse sequence is not a sweep, `tau` declares its
+        // dimension and the frequency takes the one with 5 points.
+        let yaml = r#"
+dynamic_steps: 5
+dynamic_devices:
+  mw_source:
+    config:
+      frequency: [2.8e9, 2.9e9]
+pulse_sequence:
+  amplitude: [0.1, 0.2]
+  tau: {start: 0.0, stop: 1.0e-6, unit: s, dim: 2}
+"#;
+        let container = with_metadata((2, 5, 3, 2, 2), yaml).unwrap();
+        assert_eq!(container.axes[1].as_ref().unwrap()[4], 2.9e9);
+        assert_eq!(container.axis_units[1].as_deref(), Some("Hz"));
+        assert_eq!(container.dim_names[1], "mw_source.frequency");
+        assert_eq!(container.axes[2].as_ref().unwrap().len(), 3);
+        assert_eq!(container.axis_units[2].as_deref(), Some("s"));
+        assert_eq!(container.dim_names[2], "pulse_sequence.tau");
+
+        // Both sweep dimensions have as many points as dynamic steps.
+        let yaml = r#"
+dynamic_steps: 5
+dynamic_devices:
+  mw_source:
+    config:
+      frequency: [2.8e9, 2.9e9]
+"#;
+        assert!(matches!(
+            with_metadata((2, 5, 5, 2, 2), yaml),
+            Err(QufitError::Shape(_))
+        ));
+        // Two sweeps for the same dimension.
+        let yaml = r#"
+dynamic_steps: 5
+dynamic_devices:
+  mw_source:
+    config:
+      frequency: [2.8e9, 2.9e9]
+  laser:
+    config:
+      power: [0.1, 0.5]
+"#;
+        assert!(matches!(
+            with_metadata((2, 5, 1, 2, 2), yaml),
+            Err(QufitError::Shape(_))
+        ));
+    }
+
+    #[test]
+    fn test_esr_unit_needs_coordinates() {
+        let mut container = DataContainer::from_data(Array::zeros((1, 5, 1, 1, 1)).into_dyn());
+        container.axis_units[1] = Some("MHz".to_string());
+        let model = EsrModel::Hyperfine(Hyperfine::N14);
+        // Without coordinates the axis runs over fractions of the sweep and
+        // the stored unit does not place the hyperfine lines.
+        assert!(matches!(
+            container.esr_fit(model, None, &FitOptions::default()),
+            Err(QufitError::Value(_))
+        ));
+        let result = container
+            .esr_fit(EsrModel::Lorentzian, None, &FitOptions::default())
+            .unwrap();
+        assert_eq!(result.units, ["", "", ""]);
+    }
+
+    #[test]
+    fn test_axes_need_finite_extent() {
+        let mut container = DataContainer::from_data(Array::zeros((1, 1, 4, 2, 2)).into_dyn());
+        assert!(matches!(
+            container.set_axis(2, array![0.0, 1.0, f64::NAN, 3.0], None),
+            Err(QufitError::Value(_))
+        ));
+        assert!(container.axes[2].is_none());
+        container
+            .set_axis(2, Array1::from_elem(4, 5.0), Some("ns"))
+            .unwrap();
+        assert!(matches!(
+            container.fft_frequencies(),
+            Err(QufitError::Value(_))
+        ));
+        container
+            .set_axis(2, array![0.0, 2.0, 4.0, 6.0], Some("ns"))
+            .unwrap();
+        assert_eq!(
+            container.fft_frequencies().unwrap(),
+            array![0.0, 0.125, 0.25]
+        );
+    }
+
+    #[test]
+    fn test_fits_need_a_sweep_dimension() {
+        let container = DataContainer::from_data(Array::ones((1, 5, 5, 2, 2)).into_dyn());
+        for sweep_dim in [0, 3, 4, 7] {
+            assert!(matches!(
+                container.fit(
+                    &StretchedExponential,
+                    sweep_dim,
+                    None,
+                    &FitOptions::default()
+                ),
+                Err(QufitError::Value(_))
+            ));
+        }
+        assert!(matches!(container.sweep_axis(5), Err(QufitError::Value(_))));
+    }
+}
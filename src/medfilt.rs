@@ -1,5 +1,7 @@
+use crate::error::QufitError;
 use crate::load::DataContainer;
-use ndarray::{s, Array, Array2, Array3, ArrayView2, ArrayView3, Axis};
+use crate::pipeline::Step;
+use ndarray::{s, Array, Array2, Array3, ArrayView2, ArrayView3, Axis, Ix5};
 use rayon::prelude::*;
 
 fn medfilt2d(data: &ArrayView2<f64>, kernel_size: usize) -> Array2<f64> {
@@ -25,7 +27,7 @@ fn medfilt2d(data: &ArrayView2<f64>, kernel_size: usize) -> Array2<f64> {
                 ystart_clamped..ystop_clamped
             ]);
             let mut flattened: Vec<f64> = packet.iter().cloned().collect();
-            flattened.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            flattened.sort_by(|a, b| a.total_cmp(b));
             let medindex = flattened.len() / 2 as usize;
             filtered[[i, j]] = flattened[medindex];
         }
@@ -33,7 +35,17 @@ fn medfilt2d(data: &ArrayView2<f64>, kernel_size: usize) -> Array2<f64> {
     filtered
 }
 
-pub fn medfilt_rust(data: &ArrayView3<f64>, kernel_size: usize) -> Array3<f64> {
+fn check_kernel_size(kernel_size: usize) -> Result<(), QufitError> {
+    if kernel_size == 0 {
+        return Err(QufitError::Value(
+            "kernel_size needs to be at least 1".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+pub fn medfilt_rust(data: &ArrayView3<f64>, kernel_size: usize) -> Result<Array3<f64>, QufitError> {
+    check_kernel_size(kernel_size)?;
     let zdim = data.shape()[0];
     let xdim = data.shape()[1];
     let ydim = data.shape()[2];
@@ -49,11 +61,18 @@ pub fn medfilt_rust(data: &ArrayView3<f64>, kernel_size: usize) -> Array3<f64> {
             subframe.assign(&result);
         });
 
-    filtered
+    Ok(filtered)
 }
 
 impl DataContainer {
-    pub fn medfilt_array(&self, kernel_size: usize) -> Array3<f64> {
+    pub fn medfilt_array(&self, kernel_size: usize) -> Result<Array3<f64>, QufitError> {
+        check_kernel_size(kernel_size)?;
+        if self.data.ndim() != 5 {
+            return Err(QufitError::Shape(format!(
+                "medfilt needs data with two pixel axes, but the data has shape {:?}",
+                self.data.shape()
+            )));
+        }
         let zdim = self.data.shape()[2];
         let xdim = self.data.shape()[3];
         let ydim = self.data.shape()[4];
@@ -69,7 +88,37 @@ impl DataContainer {
                 subframe.assign(&result);
             });
 
-        filtered
+        Ok(filtered)
+    }
+
+    /// Median filters every image of the data in place, for all references
+    /// and sweep points. `sigma` is left unchanged.
+    pub fn medfilt_data(&mut self, kernel_size: usize) -> Result<(), QufitError> {
+        check_kernel_size(kernel_size)?;
+        let shape = self.data.shape().to_vec();
+        let mut data = self
+            .data
+            .view_mut()
+            .into_dimensionality::<Ix5>()
+            .map_err(|_| {
+                QufitError::Shape(format!(
+                    "medfilt needs data with two pixel axes, but the data has shape {:?}",
+                    shape
+                ))
+            })?;
+        for mut reference in data.outer_iter_mut() {
+            for mut sweep in reference.outer_iter_mut() {
+                sweep
+                    .axis_iter_mut(Axis(0))
+                    .into_par_iter()
+                    .for_each(|mut frame| {
+                        let result = medfilt2d(&frame.view(), kernel_size);
+                        frame.assign(&result);
+                    });
+            }
+        }
+        self.history.steps.push(Step::Medfilt { kernel_size });
+        Ok(())
     }
 }
 
@@ -97,4 +146,22 @@ mod tests {
         ];
         assert_eq!(medfilt2d(&input.view(), 3), output);
     }
+
+    #[test]
+    fn test_medfilt_data_filters_every_frame() {
+        let mut data = Array::zeros((2, 1, 3, 5, 5));
+        data.slice_mut(s![.., .., .., 2, 2]).fill(1.0);
+        data.slice_mut(s![1, 0, 1, .., ..]).fill(2.0);
+        let mut container = DataContainer::from_data(data.into_dyn());
+        container.medfilt_data(3).unwrap();
+        let filtered = container.data.slice(s![.., 0, .., .., ..]);
+        assert!(filtered.indexed_iter().all(|((r, z, _, _), &value)| value
+            == if (r, z) == (1_usize, 1_usize) {
+                2.0
+            } else {
+                0.0
+            }));
+        let mut flat = DataContainer::from_data(Array::zeros((2, 3, 4)).into_dyn());
+        assert!(matches!(flat.medfilt_data(3), Err(QufitError::Shape(_))));
+    }
 }
@@ -0,0 +1,218 @@
+use crate::error::QufitError;
+use ndarray::Array1;
+#[cfg(feature = "python")]
+use pyo3::prelude::*;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A parameter QuPyt swept linearly from `start` to `stop` over the
+/// dynamic steps of the measurement.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "python", pyclass(get_all))]
+pub struct Sweep {
+    /// The dynamic device, or `pulse_sequence`, the parameter belongs to.
+    pub device: String,
+    pub parameter: String,
+    pub start: f64,
+    pub stop: f64,
+    /// Unit of `start` and `stop`, e.g. `Hz` for a frequency.
+    pub unit: Option<String>,
+    /// Dimension of the data the sweep runs along, if the file says so.
+    pub dim: Option<usize>,
+}
+
+impl Sweep {
+    pub fn axis(&self, steps: usize) -> Array1<f64> {
+        Array1::linspace(self.start, self.stop, steps)
+    }
+
+    pub fn name(&self) -> String {
+        format!("{}.{}", self.device, self.parameter)
+    }
+}
+
+/// Experiment parameters from the YAML/JSON file QuPyt writes next to
+/// every measurement.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "python", pyclass(get_all))]
+pub struct Metadata {
+    pub path: String,
+    pub experiment_type: Option<String>,
+    pub averages: Option<u64>,
+    pub dynamic_steps: Option<usize>,
+    pub reference_channels: Option<usize>,
+    /// Swept parameters in no particular order. [`crate::DataContainer::load`]
+    /// matches them to the dimensions of the data.
+    pub sweeps: Vec<Sweep>,
+    pub pulse_parameters: HashMap<String, f64>,
+    pub roi: Option<Vec<i64>>,
+    pub raw: String,
+}
+
+impl Metadata {
+    /// Returns the metadata sidecar belonging to a data file, i.e. a file
+    /// with the same stem and a `.yaml`, `.yml` or `.json` extension.
+    pub fn find_sidecar(data_path: &str) -> Option<PathBuf> {
+        let data_path = Path::new(data_path);
+        ["yaml", "yml", "json"]
+            .iter()
+            .map(|ext| data_path.with_extension(ext))
+            .find(|candidate| candidate.exists())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, QufitError> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| QufitError::Io(format!("Could not read {}: {}", path.display(), e)))?;
+        let raw: Value = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => serde_json::from_str(&content).map_err(|e| e.to_string()),
+            _ => serde_yaml::from_str(&content).map_err(|e| e.to_string()),
+        }
+        .map_err(|e| QufitError::Io(format!("Could not parse {}: {}", path.display(), e)))?;
+        Ok(Self::from_value(path.display().to_string(), raw))
+    }
+
+    pub(crate) fn from_value(path: String, raw: Value) -> Self {
+        let data = &raw["data"];
+        let mut sweeps = Vec::new();
+        if let Some(devices) = raw["dynamic_devices"].as_object() {
+            for (device, settings) in devices {
+                let config = settings.get("config").unwrap_or(settings);
+                sweeps.extend(Self::collect_sweeps(device, config));
+            }
+        }
+        let mut pulse_parameters = HashMap::new();
+        if let Some(sequence) = raw["pulse_sequence"].as_object() {
+            for (name, value) in sequence {
+                if let Some(value) = value.as_f64() {
+                    pulse_parameters.insert(name.clone(), value);
+                }
+            }
+            sweeps.extend(
+                sequence.iter().filter_map(|(name, value)| {
+                    Self::declared_sweep("pulse_sequence", name, value)
+                }),
+            );
+        }
+        Self {
+            path,
+            experiment_type: raw["experiment_type"].as_str().map(String::from),
+            averages: raw["averages"].as_u64(),
+            dynamic_steps: raw["dynamic_steps"]
+                .as_u64()
+                .or_else(|| data["dynamic_steps"].as_u64())
+                .map(|steps| steps as usize),
+            reference_channels: data["reference_channels"].as_u64().map(|n| n as usize),
+            sweeps,
+            pulse_parameters,
+            roi: raw["sensor"]["config"]["roi"]
+                .as_array()
+                .map(|roi| roi.iter().filter_map(Value::as_i64).collect()),
+            raw: raw.to_string(),
+        }
+    }
+
+    /// QuPyt sweeps the parameters of a dynamic device that are given as a
+    /// `[start, stop]` pair. Frequencies are in Hz.
+    fn collect_sweeps(device: &str, config: &Value) -> Vec<Sweep> {
+        let Some(config) = config.as_object() else {
+            return Vec::new();
+        };
+        config
+            .iter()
+            .filter_map(|(parameter, value)| match value.as_array()?.as_slice() {
+                [start, stop] => Some(Sweep {
+                    device: device.to_string(),
+                    parameter: parameter.clone(),
+                    start: start.as_f64()?,
+                    stop: stop.as_f64()?,
+                    unit: parameter
+                        .to_lowercase()
+                        .contains("frequency")
+                        .then(|| "Hz".to_string()),
+                    dim: None,
+                }),
+                _ => Self::declared_sweep(device, parameter, value),
+            })
+            .collect()
+    }
+
+    /// A sweep written out as `{start, stop}`, optionally with its `unit`
+    /// and the `dim` of the data it runs along. This is the only form
+    /// recognised in the pulse sequence, where pairs are also used for
+    /// parameters that are not swept.
+    fn declared_sweep(device: &str, parameter: &str, value: &Value) -> Option<Sweep> {
+        Some(Sweep {
+            device: device.to_string(),
+            parameter: parameter.to_string(),
+            start: value.get("start")?.as_f64()?,
+            stop: value.get("stop")?.as_f64()?,
+            unit: value.get("unit").and_then(Value::as_str).map(String::from),
+            dim: value
+                .get("dim")
+                .and_then(Value::as_u64)
+                .map(|dim| dim as usize),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_qupyt_yaml() {
+        let yaml = r#"
+experiment_type: ODMR
+averages: 200
+dynamic_steps: 51
+sensor:
+  type: Basler
+  config:
+    exposure_time: 1000
+    roi: [0, 0, 64, 64]
+dynamic_devices:
+  mw_source:
+    device_type: WindFreak
+    config:
+      frequency: [2.80e9, 2.95e9]
+      amplitude: 10
+pulse_sequence:
+  laserduration: 3.0
+  readout_window: [0.0, 0.5]
+  tau: {start: 0.0, stop: 2.0e-6, unit: s, dim: 2}
+data:
+  reference_channels: 2
+"#;
+        let raw: Value = serde_yaml::from_str(yaml).unwrap();
+        let metadata = Metadata::from_value("test.yaml".to_string(), raw);
+        assert_eq!(metadata.experiment_type.as_deref(), Some("ODMR"));
+        assert_eq!(metadata.averages, Some(200));
+        assert_eq!(metadata.dynamic_steps, Some(51));
+        assert_eq!(metadata.reference_channels, Some(2));
+        assert_eq!(metadata.roi, Some(vec![0, 0, 64, 64]));
+        assert_eq!(metadata.pulse_parameters.get("laserduration"), Some(&3.0));
+        assert_eq!(
+            metadata.sweeps,
+            vec![
+                Sweep {
+                    device: "mw_source".to_string(),
+                    parameter: "frequency".to_string(),
+                    start: 2.80e9,
+                    stop: 2.95e9,
+                    unit: Some("Hz".to_string()),
+                    dim: None,
+                },
+                Sweep {
+                    device: "pulse_sequence".to_string(),
+                    parameter: "tau".to_string(),
+                    start: 0.0,
+                    stop: 2.0e-6,
+                    unit: Some("s".to_string()),
+                    dim: Some(2),
+                },
+            ]
+        );
+    }
+}
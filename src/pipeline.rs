@@ -0,0 +1,471 @@
+use crate::error::QufitError;
+use crate::fit_esr_nalgebra::EsrModel;
+use crate::fit_loss::{Loss, RobustLoss};
+use crate::fit_model::{param_index, Constraints, FitModel};
+use crate::fit_multi_esr_nalgebra::MultiLorentzian;
+use crate::fit_rabi_nalgebra::{DampedRabi, Envelope, GuessStrategy, Rabi};
+use crate::fit_result::FitResult;
+use crate::fit_t1_nalgebra::StretchedExponential;
+use crate::load::{DataContainer, FitOptions, Sigma};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// `lower` and `upper` limit of a parameter, either may be left open.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Bound {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lower: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upper: Option<f64>,
+}
+
+/// The keywords shared by all fit steps, see [`FitOptions`]. Only
+/// `"shot_noise"` can be given as `sigma`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FitConfig {
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub bounds: BTreeMap<String, Bound>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub fixed: BTreeMap<String, f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sigma: Option<String>,
+    pub loss: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub f_scale: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coarse_bin: Option<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub shared: Vec<String>,
+}
+
+impl Default for FitConfig {
+    fn default() -> Self {
+        FitConfig {
+            bounds: BTreeMap::new(),
+            fixed: BTreeMap::new(),
+            sigma: None,
+            loss: "linear".to_string(),
+            f_scale: None,
+            coarse_bin: None,
+            shared: Vec::new(),
+        }
+    }
+}
+
+impl FitConfig {
+    pub fn options(&self) -> Result<FitOptions, QufitError> {
+        let sigma = match self.sigma.as_deref() {
+            None => None,
+            Some("shot_noise") => Some(Sigma::ShotNoise),
+            Some(other) => {
+                return Err(QufitError::Value(format!(
+                    "Unknown sigma '{}'. Pipelines only support \"shot_noise\"",
+                    other
+                )))
+            }
+        };
+        Ok(FitOptions {
+            bounds: self
+                .bounds
+                .iter()
+                .map(|(name, bound)| (name.clone(), (bound.lower, bound.upper)))
+                .collect(),
+            fixed: self.fixed.clone().into_iter().collect(),
+            sigma,
+            loss: RobustLoss {
+                loss: Loss::from_str(&self.loss).map_err(QufitError::Value)?,
+                f_scale: self.f_scale,
+            },
+            coarse_bin: self.coarse_bin,
+            shared: self.shared.clone(),
+        })
+    }
+}
+
+fn default_esr_model() -> String {
+    "lorentzian".to_string()
+}
+
+fn default_guess() -> String {
+    "fft".to_string()
+}
+
+/// One step of a [`Pipeline`]: a [`DataContainer`] method and its
+/// arguments. Written as a table with the method name under `step`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "step", rename_all = "snake_case")]
+pub enum Step {
+    ReferenceRatio,
+    ReferenceSum,
+    CompressData {
+        stepsize: usize,
+    },
+    Medfilt {
+        kernel_size: usize,
+    },
+    EsrFit {
+        #[serde(default = "default_esr_model")]
+        model: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        axis_unit: Option<String>,
+        #[serde(flatten)]
+        fit: FitConfig,
+    },
+    EsrMultiFit {
+        n_peaks: usize,
+        #[serde(default)]
+        shared_width: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        axis_unit: Option<String>,
+        #[serde(flatten)]
+        fit: FitConfig,
+    },
+    RabiFit {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        damping: Option<String>,
+        #[serde(default = "default_guess")]
+        guess: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        axis_unit: Option<String>,
+        #[serde(flatten)]
+        fit: FitConfig,
+    },
+    T1Fit {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        axis_unit: Option<String>,
+        #[serde(flatten)]
+        fit: FitConfig,
+    },
+}
+
+impl Step {
+    pub fn is_fit(&self) -> bool {
+        matches!(
+            self,
+            Step::EsrFit { .. }
+                | Step::EsrMultiFit { .. }
+                | Step::RabiFit { .. }
+                | Step::T1Fit { .. }
+        )
+    }
+
+    /// Checks that the parameters a fit step bounds, fixes or shares exist
+    /// in its model. The parameter names of the hyperfine models do not
+    /// depend on the axis unit, which may only come with the data, so Hz
+    /// stands in for it here.
+    fn check_names(&self) -> Result<(), QufitError> {
+        let (model, fit): (Box<dyn FitModel>, _) = match self {
+            Step::EsrFit {
+                model,
+                axis_unit,
+                fit,
+            } => {
+                let model = EsrModel::from_str(model).map_err(QufitError::Value)?;
+                (
+                    model.fit_model(Some(axis_unit.as_deref().unwrap_or("Hz")))?,
+                    fit,
+                )
+            }
+            Step::EsrMultiFit {
+                n_peaks,
+                shared_width,
+                fit,
+                ..
+            } => (
+                Box::new(MultiLorentzian {
+                    n_peaks: *n_peaks,
+                    shared_width: *shared_width,
+                }),
+                fit,
+            ),
+            Step::RabiFit {
+                damping,
+                guess,
+                fit,
+                ..
+            } => {
+                let strategy = GuessStrategy::from_str(guess).map_err(QufitError::Value)?;
+                match damping {
+                    Some(damping) => {
+                        let envelope = Envelope::from_str(damping).map_err(QufitError::Value)?;
+                        (Box::new(DampedRabi { envelope, strategy }), fit)
+                    }
+                    None => (Box::new(Rabi { strategy }), fit),
+                }
+            }
+            Step::T1Fit { fit, .. } => (Box::new(StretchedExponential), fit),
+            _ => return Ok(()),
+        };
+        let options = fit.options()?;
+        Constraints::from_names(model.as_ref(), &options.bounds, &options.fixed)?;
+        for name in &options.shared {
+            param_index(model.as_ref(), name).map_err(QufitError::Value)?;
+        }
+        Ok(())
+    }
+
+    /// Applies a processing step to `container`, or fits it. Processing
+    /// steps return `None`.
+    fn apply(&self, container: &mut DataContainer) -> Result<Option<FitResult>, QufitError> {
+        let result = match self {
+            Step::ReferenceRatio => return container.reference_ratio().map(|_| None),
+            Step::ReferenceSum => return container.reference_sum().map(|_| None),
+            Step::CompressData { stepsize } => {
+                return container.compress_data(*stepsize).map(|_| None)
+            }
+            Step::Medfilt { kernel_size } => {
+                return container.medfilt_data(*kernel_size).map(|_| None)
+            }
+            Step::EsrFit {
+                model,
+                axis_unit,
+                fit,
+            } => {
+                let model = EsrModel::from_str(model).map_err(QufitError::Value)?;
+                container.esr_fit(model, axis_unit.as_deref(), &fit.options()?)
+            }
+            Step::EsrMultiFit {
+                n_peaks,
+                shared_width,
+                axis_unit,
+                fit,
+            } => container.esr_multi_fit(
+                *n_peaks,
+                *shared_width,
+                axis_unit.as_deref(),
+                &fit.options()?,
+            ),
+            Step::RabiFit {
+                damping,
+                guess,
+                axis_unit,
+                fit,
+            } => {
+                let damping = damping
+                    .as_deref()
+                    .map(Envelope::from_str)
+                    .transpose()
+                    .map_err(QufitError::Value)?;
+                let strategy = GuessStrategy::from_str(guess).map_err(QufitError::Value)?;
+                container.rabi_fit(damping, strategy, axis_unit.as_deref(), &fit.options()?)
+            }
+            Step::T1Fit { axis_unit, fit } => {
+                container.t1_fit(axis_unit.as_deref(), &fit.options()?)
+            }
+        };
+        let mut result = result?;
+        result.history.steps.push(self.clone());
+        Ok(Some(result))
+    }
+}
+
+/// A chain of processing steps, optionally ending in a fit. Read from and
+/// written to TOML, YAML or JSON, e.g.
+///
+/// ```toml
+/// [[steps]]
+/// step = "reference_ratio"
+///
+/// [[steps]]
+/// step = "compress_data"
+/// stepsize = 2
+///
+/// [[steps]]
+/// step = "esr_fit"
+/// model = "lorentzian"
+/// bounds.x0 = { lower = 2860.0, upper = 2880.0 }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Pipeline {
+    #[serde(default)]
+    pub steps: Vec<Step>,
+}
+
+impl Pipeline {
+    /// Reads a pipeline from a `.toml`, `.yaml`, `.yml` or `.json` file.
+    pub fn load(path: &Path) -> Result<Self, QufitError> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| QufitError::Io(format!("Could not read {}: {}", path.display(), e)))?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Self::from_toml(&content),
+            Some("json") => serde_json::from_str(&content).map_err(|e| e.to_string()),
+            _ => Self::from_yaml(&content),
+        }
+        .map_err(|e| QufitError::Io(format!("Could not parse {}: {}", path.display(), e)))
+    }
+
+    /// Writes the pipeline in the format given by the extension of `path`.
+    pub fn save(&self, path: &Path) -> Result<(), QufitError> {
+        let content = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => self.to_toml(),
+            Some("json") => serde_json::to_string_pretty(self).map_err(|e| e.to_string()),
+            _ => self.to_yaml(),
+        }
+        .map_err(QufitError::Value)?;
+        fs::write(path, content)
+            .map_err(|e| QufitError::Io(format!("Could not write {}: {}", path.display(), e)))
+    }
+
+    pub fn from_toml(content: &str) -> Result<Self, String> {
+        toml::from_str(content).map_err(|e| e.to_string())
+    }
+
+    pub fn from_yaml(content: &str) -> Result<Self, String> {
+        serde_yaml::from_str(content).map_err(|e| e.to_string())
+    }
+
+    pub fn to_toml(&self) -> Result<String, String> {
+        toml::to_string(self).map_err(|e| e.to_string())
+    }
+
+    pub fn to_yaml(&self) -> Result<String, String> {
+        serde_yaml::to_string(self).map_err(|e| e.to_string())
+    }
+
+    /// Checks that only the last step fits, and that the parameters it
+    /// bounds, fixes or shares exist in its model, without any data.
+    pub fn check(&self) -> Result<(), QufitError> {
+        let last = self.steps.len().saturating_sub(1);
+        if self.steps[..last].iter().any(Step::is_fit) {
+            return Err(QufitError::Value(
+                "A fit can only be the last step of a pipeline".to_string(),
+            ));
+        }
+        match self.steps.last() {
+            Some(step) => step.check_names(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl DataContainer {
+    /// Runs the steps of `pipeline` in order. Returns the result of the
+    /// final fit step, if there is one. Every step is recorded in
+    /// `history`, and the fit result records the whole chain, so that it
+    /// can be replayed on the raw data.
+    pub fn run_pipeline(&mut self, pipeline: &Pipeline) -> Result<Option<FitResult>, QufitError> {
+        pipeline.check()?;
+        let mut result = None;
+        for step in &pipeline.steps {
+            result = step.apply(self)?;
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fit_esr_nalgebra::Lorentzian;
+    use crate::fit_model::FitModel;
+    use nalgebra::DVector;
+    use ndarray::{s, Array1, Array5};
+
+    const TOML: &str = r#"
+[[steps]]
+step = "reference_ratio"
+
+[[steps]]
+step = "compress_data"
+stepsize = 2
+
+[[steps]]
+step = "esr_fit"
+axis_unit = "GHz"
+fixed = { gamma = 0.05 }
+bounds.x0 = { lower = 0.3 }
+"#;
+
+    const YAML: &str = "
+steps:
+  - step: reference_ratio
+  - step: compress_data
+    stepsize: 2
+  - step: esr_fit
+    axis_unit: GHz
+    fixed:
+      gamma: 0.05
+    bounds:
+      x0:
+        lower: 0.3
+";
+
+    #[test]
+    fn test_toml_and_yaml_agree() {
+        let pipeline = Pipeline::from_toml(TOML).unwrap();
+        assert_eq!(pipeline, Pipeline::from_yaml(YAML).unwrap());
+        let Step::EsrFit { model, fit, .. } = &pipeline.steps[2] else {
+            panic!("expected an ESR fit, got {:?}", pipeline.steps[2]);
+        };
+        assert_eq!(model, "lorentzian");
+        assert_eq!(fit.loss, "linear");
+        assert_eq!(fit.bounds["x0"].lower, Some(0.3));
+        for text in [pipeline.to_toml().unwrap(), pipeline.to_yaml().unwrap()] {
+            let reread = Pipeline::from_toml(&text).or_else(|_| Pipeline::from_yaml(&text));
+            assert_eq!(reread.unwrap(), pipeline);
+        }
+    }
+
+    #[test]
+    fn test_run_and_replay() {
+        let x = Array1::linspace(0.0, 1.0, 51);
+        let truth = DVector::from_vec(vec![0.01, 0.05, 0.5]);
+        let trace = x.mapv(|x| Lorentzian.evaluate(x, &truth));
+        let mut data = Array5::from_elem((2, 51, 1, 4, 4), 1000.0);
+        for i in 0..4 {
+            for j in 0..4 {
+                let counts = trace.mapv(|y| 1000.0 * y);
+                data.slice_mut(s![0, .., 0, i, j]).assign(&counts);
+            }
+        }
+        let raw = DataContainer::from_data(data.into_dyn());
+
+        let mut container = DataContainer::from_data(raw.data.clone());
+        let pipeline = Pipeline::from_toml(TOML).unwrap();
+        let result = container.run_pipeline(&pipeline).unwrap().unwrap();
+        assert_eq!(container.data.shape(), [1, 51, 1, 2, 2]);
+        assert_eq!(container.history.steps, pipeline.steps[..2]);
+        assert_eq!(result.history, pipeline);
+        let x0 = result.index_of("x0").unwrap();
+        assert!((result.fit.params[[1, 1, x0]] - 0.5).abs() < 1e-6);
+
+        let mut replayed = DataContainer::from_data(raw.data.clone());
+        let again = replayed.run_pipeline(&result.history).unwrap().unwrap();
+        assert_eq!(again.fit.params, result.fit.params);
+
+        let mut misplaced = pipeline.clone();
+        misplaced.steps.swap(1, 2);
+        assert!(matches!(
+            DataContainer::from_data(raw.data).run_pipeline(&misplaced),
+            Err(QufitError::Value(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_names_parameters_of_the_model() {
+        let esr_fit = |model: &str, fixed: &str| {
+            let mut fit = FitConfig::default();
+            fit.fixed.insert(fixed.to_string(), 0.5);
+            Pipeline {
+                steps: vec![Step::EsrFit {
+                    model: model.to_string(),
+                    axis_unit: None,
+                    fit,
+                }],
+            }
+        };
+        assert!(esr_fit("lorentzian", "gamma").check().is_ok());
+        assert!(esr_fit("n14", "gamma").check().is_ok());
+        match esr_fit("lorentzian", "width").check() {
+            Err(QufitError::Value(message)) => {
+                assert!(message.contains("'width'"), "{}", message);
+                assert!(message.contains("gamma"), "{}", message);
+            }
+            other => panic!("expected a value error, got {:?}", other),
+        }
+    }
+}
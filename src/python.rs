@@ -0,0 +1,601 @@
+// create_exception! of pyo3 0.19 checks cfg(addr_of), which newer compilers
+// do not know.
+#![allow(unexpected_cfgs)]
+
+use crate::error::QufitError;
+use crate::fit_common::FitStatus;
+use crate::fit_esr_nalgebra::EsrModel;
+use crate::fit_loss::{Loss, RobustLoss};
+use crate::fit_model::NamedBounds;
+use crate::fit_rabi_nalgebra::{Envelope, GuessStrategy};
+use crate::fit_result::FitResult;
+use crate::load::{DataContainer, FitOptions, Sigma};
+use crate::medfilt::medfilt_rust;
+use crate::metadata::{Metadata, Sweep};
+use crate::pipeline::Pipeline;
+use ndarray::{ArrayD, Axis};
+use numpy::{Element, IntoPyArray, PyArray1, PyArrayDyn};
+use pyo3::create_exception;
+use pyo3::exceptions::{PyIOError, PyKeyError, PyRuntimeError, PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use pyo3::wrap_pyfunction;
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+create_exception!(
+    qufit,
+    ShapeError,
+    PyValueError,
+    "An array does not have the shape or number of dimensions an operation needs."
+);
+create_exception!(
+    qufit,
+    DtypeError,
+    PyTypeError,
+    "An array or file has an unsupported element type."
+);
+create_exception!(
+    qufit,
+    FitError,
+    PyRuntimeError,
+    "A fit could not be carried out on the data."
+);
+
+impl From<QufitError> for PyErr {
+    fn from(error: QufitError) -> Self {
+        match error {
+            QufitError::Io(message) => PyIOError::new_err(message),
+            QufitError::Shape(message) => ShapeError::new_err(message),
+            QufitError::Dtype(message) => DtypeError::new_err(message),
+            QufitError::Fit(message) => FitError::new_err(message),
+            QufitError::Value(message) => PyValueError::new_err(message),
+        }
+    }
+}
+
+#[pymodule]
+fn qufit(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<DataContainer>()?;
+    m.add_class::<FitResult>()?;
+    m.add_class::<Metadata>()?;
+    m.add_class::<Sweep>()?;
+    m.add_function(wrap_pyfunction!(medfilt_pyth, m)?)?;
+    m.add("ShapeError", py.get_type::<ShapeError>())?;
+    m.add("DtypeError", py.get_type::<DtypeError>())?;
+    m.add("FitError", py.get_type::<FitError>())?;
+    Ok(())
+}
+
+#[pyfunction]
+fn medfilt_pyth<'py>(
+    py: Python<'py>,
+    input: &PyArrayDyn<f64>,
+    kernel_size: usize,
+) -> PyResult<&'py PyArrayDyn<f64>> {
+    let input_array = input.readonly();
+    let input_array = input_array
+        .as_array()
+        .into_dimensionality::<ndarray::Ix3>()
+        .map_err(|_| {
+            QufitError::Shape(format!(
+                "medfilt needs a 3D array, got shape {:?}",
+                input.shape()
+            ))
+        })?;
+    let result = medfilt_rust(&input_array, kernel_size)?;
+    Ok(result.into_dyn().into_pyarray(py))
+}
+
+/// Copies `array` into an f64 array if it is a numpy array of dtype `T`.
+fn convert_py_array<T: Element + Copy>(
+    array: &PyAny,
+    convert: fn(T) -> f64,
+) -> Option<ArrayD<f64>> {
+    let array = array.downcast::<PyArrayDyn<T>>().ok()?;
+    Some(array.readonly().as_array().mapv(convert))
+}
+
+/// Converts a numpy array of any supported numeric dtype to f64.
+fn array_from_py(array: &PyAny) -> Result<ArrayD<f64>, QufitError> {
+    convert_py_array::<u8>(array, |x| x as f64)
+        .or_else(|| convert_py_array::<u16>(array, |x| x as f64))
+        .or_else(|| convert_py_array::<u32>(array, |x| x as f64))
+        .or_else(|| convert_py_array::<u64>(array, |x| x as f64))
+        .or_else(|| convert_py_array::<i32>(array, |x| x as f64))
+        .or_else(|| convert_py_array::<i64>(array, |x| x as f64))
+        .or_else(|| convert_py_array::<f32>(array, |x| x as f64))
+        .or_else(|| convert_py_array::<f64>(array, |x| x))
+        .ok_or_else(|| {
+            QufitError::Dtype(
+                "Expected a numpy array of dtype uint8, uint16, uint32, uint64, int32, int64, float32 or float64"
+                    .to_string(),
+            )
+        })
+}
+
+/// Keywords shared by all Python fit methods.
+fn fit_options(
+    bounds: Option<NamedBounds>,
+    fixed: Option<HashMap<String, f64>>,
+    sigma: Option<&PyAny>,
+    loss: &str,
+    f_scale: Option<f64>,
+    coarse_bin: Option<usize>,
+    shared: Option<Vec<String>>,
+) -> PyResult<FitOptions> {
+    let sigma = match sigma {
+        None => None,
+        Some(sigma) => match sigma.extract::<&str>() {
+            Ok("shot_noise") => Some(Sigma::ShotNoise),
+            Ok(other) => {
+                return Err(PyValueError::new_err(format!(
+                    "Unknown sigma '{}'. Pass an array or \"shot_noise\"",
+                    other
+                )))
+            }
+            Err(_) => Some(Sigma::Values(array_from_py(sigma)?)),
+        },
+    };
+    Ok(FitOptions {
+        bounds: bounds.unwrap_or_default(),
+        fixed: fixed.unwrap_or_default(),
+        sigma,
+        loss: RobustLoss {
+            loss: Loss::from_str(loss).map_err(QufitError::Value)?,
+            f_scale,
+        },
+        coarse_bin,
+        shared: shared.unwrap_or_default(),
+    })
+}
+
+#[pymethods]
+impl DataContainer {
+    #[new]
+    fn py_new(path: String) -> PyResult<Self> {
+        Ok(Self::load(&path)?)
+    }
+
+    fn get_metadata(&self) -> Option<Metadata> {
+        self.metadata.clone()
+    }
+
+    fn get_dim_names(&self) -> Vec<String> {
+        self.dim_names.clone()
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (array, axes=None))]
+    fn from_array(array: &PyAny, axes: Option<Vec<Option<&PyArray1<f64>>>>) -> PyResult<Self> {
+        let mut container = Self::from_data(array_from_py(array)?);
+        if let Some(axes) = axes {
+            if axes.len() > container.data.ndim() {
+                return Err(QufitError::Shape(format!(
+                    "Got {} axes for an array with {} dimensions",
+                    axes.len(),
+                    container.data.ndim()
+                ))
+                .into());
+            }
+            for (dim, values) in axes.into_iter().enumerate() {
+                if let Some(values) = values {
+                    container.set_axis(dim, values.to_owned_array(), None)?;
+                }
+            }
+        }
+        Ok(container)
+    }
+
+    /// Replaces the data in place. Axis coordinates are kept for all axes
+    /// whose length did not change.
+    #[pyo3(name = "set_data")]
+    fn py_set_data(&mut self, array: &PyAny) -> PyResult<()> {
+        self.set_data(array_from_py(array)?);
+        Ok(())
+    }
+
+    /// Sets the standard deviation of every data point, or clears it with
+    /// `None`. Used by the fits with `sigma="shot_noise"`.
+    #[pyo3(name = "set_sigma", signature = (sigma=None))]
+    fn py_set_sigma(&mut self, sigma: Option<&PyAny>) -> PyResult<()> {
+        let sigma = sigma.map(array_from_py).transpose()?;
+        Ok(self.set_sigma(sigma)?)
+    }
+
+    fn get_sigma(&self, py: Python<'_>) -> Option<PyObject> {
+        self.sigma
+            .clone()
+            .map(|sigma| sigma.into_pyarray(py).to_object(py))
+    }
+
+    /// Sets the coordinates along `dim` and their `unit`, e.g. `"Hz"`.
+    #[pyo3(name = "set_axis", signature = (dim, values, unit=None))]
+    fn py_set_axis(
+        &mut self,
+        dim: usize,
+        values: &PyArray1<f64>,
+        unit: Option<&str>,
+    ) -> PyResult<()> {
+        Ok(self.set_axis(dim, values.to_owned_array(), unit)?)
+    }
+
+    fn get_axis_units(&self) -> Vec<Option<String>> {
+        self.axis_units.clone()
+    }
+
+    fn get_axis(&self, dim: usize, py: Python<'_>) -> PyResult<PyObject> {
+        Ok(self.axis(dim)?.into_pyarray(py).to_object(py))
+    }
+
+    /// Divides the signal by the reference along the first axis.
+    #[pyo3(name = "reference_ratio")]
+    fn py_reference_ratio(&mut self) -> PyResult<()> {
+        Ok(self.reference_ratio()?)
+    }
+
+    /// Contrast `(signal - reference) / (signal + reference)` along the
+    /// first axis.
+    #[pyo3(name = "reference_sum")]
+    fn py_reference_sum(&mut self) -> PyResult<()> {
+        Ok(self.reference_sum()?)
+    }
+
+    /// Averages blocks of `stepsize` pixels. A known `sigma` is propagated
+    /// to the standard deviation of the block means.
+    #[pyo3(name = "compress_data")]
+    fn py_compress_data(&mut self, stepsize: usize) -> PyResult<()> {
+        Ok(self.compress_data(stepsize)?)
+    }
+
+    /// `model` selects the lineshape. `axis_unit` overrides the unit of the
+    /// sweep axis, which otherwise comes from the metadata or `set_axis`.
+    /// The hyperfine models need it to be `Hz`, `kHz`, `MHz` or `GHz` to
+    /// place the lines.
+    ///
+    /// In all fits, parameters measured along the sweep axis (widths,
+    /// centers, times) carry the unit of the axis in `FitResult.units`:
+    /// `a.u.` if the axis has coordinates of unknown unit, and no unit if it
+    /// has none and the sweep runs from 0 to 1.
+    ///
+    /// All fit methods take `bounds`, a dict mapping parameter names to
+    /// `(lower, upper)` with `None` for an open side, and `fixed`, a dict
+    /// mapping parameter names to the value they are held at. The bounds
+    /// narrow those of the model, so widths and times stay positive;
+    /// bounds without room between them raise a `FitError`. `sigma`
+    /// weights the residuals: either an array of standard deviations with
+    /// the shape of the data, or `"shot_noise"` for the uncertainty
+    /// propagated from the raw counts by `reference_ratio`/`reference_sum`.
+    /// These are taken as absolute uncertainties, so the errors come from
+    /// the unscaled covariance; without `sigma` the covariance is scaled by
+    /// the reduced chi-square.
+    /// `loss` (`linear`, `huber`, `soft_l1` or `cauchy`) makes the fit
+    /// robust against outliers; residuals beyond `f_scale` (in units of
+    /// `sigma` if given) are down-weighted. Without `f_scale` it is
+    /// estimated for every pixel from the spread of the residuals.
+    /// With `coarse_bin`, blocks of `coarse_bin` x `coarse_bin` pixels are
+    /// averaged and fitted first, and every pixel starts from the result of
+    /// its block. This helps noisy or low-contrast pixels converge.
+    /// `shared` lists parameters that are common to the whole field of
+    /// view, e.g. a linewidth set by the microwave antenna. They are fitted
+    /// jointly to all pixels together with the local parameters of every
+    /// pixel; robust losses are not supported for such global fits.
+    #[pyo3(name = "esr_fit", signature = (model="lorentzian", axis_unit=None, bounds=None, fixed=None, sigma=None, loss="linear", f_scale=None, coarse_bin=None, shared=None))]
+    #[allow(clippy::too_many_arguments)]
+    fn py_esr_fit(
+        &self,
+        model: &str,
+        axis_unit: Option<&str>,
+        bounds: Option<NamedBounds>,
+        fixed: Option<HashMap<String, f64>>,
+        sigma: Option<&PyAny>,
+        loss: &str,
+        f_scale: Option<f64>,
+        coarse_bin: Option<usize>,
+        shared: Option<Vec<String>>,
+    ) -> PyResult<FitResult> {
+        let model = EsrModel::from_str(model).map_err(QufitError::Value)?;
+        let options = fit_options(bounds, fixed, sigma, loss, f_scale, coarse_bin, shared)?;
+        Ok(self.esr_fit(model, axis_unit, &options)?)
+    }
+
+    #[pyo3(name = "esr_multi_fit", signature = (n_peaks, shared_width=false, axis_unit=None, bounds=None, fixed=None, sigma=None, loss="linear", f_scale=None, coarse_bin=None, shared=None))]
+    #[allow(clippy::too_many_arguments)]
+    fn py_esr_multi_fit(
+        &self,
+        n_peaks: usize,
+        shared_width: bool,
+        axis_unit: Option<&str>,
+        bounds: Option<NamedBounds>,
+        fixed: Option<HashMap<String, f64>>,
+        sigma: Option<&PyAny>,
+        loss: &str,
+        f_scale: Option<f64>,
+        coarse_bin: Option<usize>,
+        shared: Option<Vec<String>>,
+    ) -> PyResult<FitResult> {
+        let options = fit_options(bounds, fixed, sigma, loss, f_scale, coarse_bin, shared)?;
+        Ok(self.esr_multi_fit(n_peaks, shared_width, axis_unit, &options)?)
+    }
+
+    /// Without `damping` an undamped cosine is fitted. With `damping` set to
+    /// `exponential`, `gaussian` or `stretched` the decay time is fitted as
+    /// an additional parameter (and the stretch exponent for `stretched`).
+    /// `guess` selects how the starting point is estimated: `fft` or `argmin`.
+    #[pyo3(name = "rabi_fit", signature = (damping=None, guess="fft", axis_unit=None, bounds=None, fixed=None, sigma=None, loss="linear", f_scale=None, coarse_bin=None, shared=None))]
+    #[allow(clippy::too_many_arguments)]
+    fn py_rabi_fit(
+        &self,
+        damping: Option<&str>,
+        guess: &str,
+        axis_unit: Option<&str>,
+        bounds: Option<NamedBounds>,
+        fixed: Option<HashMap<String, f64>>,
+        sigma: Option<&PyAny>,
+        loss: &str,
+        f_scale: Option<f64>,
+        coarse_bin: Option<usize>,
+        shared: Option<Vec<String>>,
+    ) -> PyResult<FitResult> {
+        let strategy = GuessStrategy::from_str(guess).map_err(QufitError::Value)?;
+        let envelope = damping
+            .map(Envelope::from_str)
+            .transpose()
+            .map_err(QufitError::Value)?;
+        let options = fit_options(bounds, fixed, sigma, loss, f_scale, coarse_bin, shared)?;
+        Ok(self.rabi_fit(envelope, strategy, axis_unit, &options)?)
+    }
+
+    #[pyo3(name = "t1_fit", signature = (axis_unit=None, bounds=None, fixed=None, sigma=None, loss="linear", f_scale=None, coarse_bin=None, shared=None))]
+    #[allow(clippy::too_many_arguments)]
+    fn py_t1_fit(
+        &self,
+        axis_unit: Option<&str>,
+        bounds: Option<NamedBounds>,
+        fixed: Option<HashMap<String, f64>>,
+        sigma: Option<&PyAny>,
+        loss: &str,
+        f_scale: Option<f64>,
+        coarse_bin: Option<usize>,
+        shared: Option<Vec<String>>,
+    ) -> PyResult<FitResult> {
+        let options = fit_options(bounds, fixed, sigma, loss, f_scale, coarse_bin, shared)?;
+        Ok(self.t1_fit(axis_unit, &options)?)
+    }
+
+    /// Runs the steps of a TOML, YAML or JSON pipeline file. Returns the
+    /// result of the final fit step, or `None` if there is none.
+    #[pyo3(name = "run_pipeline")]
+    fn py_run_pipeline(&mut self, path: &str) -> PyResult<Option<FitResult>> {
+        let pipeline = Pipeline::load(Path::new(path))?;
+        Ok(self.run_pipeline(&pipeline)?)
+    }
+
+    /// Writes the data, `sigma` and a JSON header with the axes, the history
+    /// and the metadata to the folder `path`. Read it back with
+    /// `DataContainer(path)`.
+    #[pyo3(name = "save_npy")]
+    fn py_save_npy(&self, path: &str) -> PyResult<()> {
+        Ok(self.save_npy(Path::new(path))?)
+    }
+
+    /// Like `save_npy`, bundled into one .npz file.
+    #[pyo3(name = "save_npz")]
+    fn py_save_npz(&self, path: &str) -> PyResult<()> {
+        Ok(self.save_npz(Path::new(path))?)
+    }
+
+    /// Processing steps applied since loading, as a TOML pipeline.
+    fn get_history(&self) -> PyResult<String> {
+        Ok(self.history.to_toml().map_err(QufitError::Value)?)
+    }
+
+    fn get_data(&self, py: Python<'_>) -> PyObject {
+        self.data.clone().into_pyarray(py).to_object(py)
+    }
+
+    fn fft(&self, py: Python<'_>) -> PyResult<PyObject> {
+        Ok(self.array_fft()?.into_pyarray(py).to_object(py))
+    }
+
+    fn fft_freq(&self, py: Python<'_>) -> PyResult<PyObject> {
+        Ok(self.fft_frequencies()?.into_pyarray(py).to_object(py))
+    }
+
+    fn hilbert(&self, py: Python<'_>) -> PyResult<PyObject> {
+        Ok(self.array_hilbert()?.into_pyarray(py).to_object(py))
+    }
+
+    fn medfilt(&self, kernel_size: usize, py: Python<'_>) -> PyResult<PyObject> {
+        Ok(self
+            .medfilt_array(kernel_size)?
+            .into_pyarray(py)
+            .to_object(py))
+    }
+}
+
+impl FitResult {
+    fn checked_index(&self, name: &str) -> PyResult<usize> {
+        self.index_of(name).ok_or_else(|| {
+            PyKeyError::new_err(format!(
+                "{} has no parameter '{}'. Parameters: {}",
+                self.model,
+                name,
+                self.param_names.join(", ")
+            ))
+        })
+    }
+}
+
+#[pymethods]
+impl FitResult {
+    #[getter]
+    fn model(&self) -> String {
+        self.model.clone()
+    }
+
+    #[getter]
+    fn param_names(&self) -> Vec<String> {
+        self.param_names.clone()
+    }
+
+    #[getter]
+    fn units(&self) -> Vec<String> {
+        self.units.clone()
+    }
+
+    /// Unit of the sweep axis the traces were fitted against, if known.
+    #[getter]
+    fn axis_unit(&self) -> Option<String> {
+        self.axis_unit.clone()
+    }
+
+    /// QuPyt metadata of the fitted measurement, if known.
+    #[getter]
+    fn metadata(&self) -> Option<Metadata> {
+        self.metadata.clone()
+    }
+
+    /// Reads a result saved with `save_npy` (a folder) or `save_npz`.
+    #[staticmethod]
+    #[pyo3(name = "load")]
+    fn py_load(path: &str) -> PyResult<Self> {
+        Ok(Self::load(Path::new(path))?)
+    }
+
+    /// Writes one .npy file per parameter map, error map and diagnostic,
+    /// and a JSON header with the model, units, sweep axis, history and
+    /// metadata to the folder `path`.
+    #[pyo3(name = "save_npy")]
+    fn py_save_npy(&self, path: &str) -> PyResult<()> {
+        Ok(self.save_npy(Path::new(path))?)
+    }
+
+    /// Like `save_npy`, bundled into one .npz file. The header is stored as
+    /// the UTF-8 bytes of the `header` entry.
+    #[pyo3(name = "save_npz")]
+    fn py_save_npz(&self, path: &str) -> PyResult<()> {
+        Ok(self.save_npz(Path::new(path))?)
+    }
+
+    /// The pipeline that produced this result, as TOML. Save it to a file
+    /// to replay the analysis with `DataContainer.run_pipeline`.
+    #[getter]
+    fn history(&self) -> PyResult<String> {
+        Ok(self.history.to_toml().map_err(QufitError::Value)?)
+    }
+
+    /// All parameter maps, with the parameters along the last axis.
+    #[getter]
+    fn params(&self, py: Python<'_>) -> PyObject {
+        self.fit.params.clone().into_pyarray(py).to_object(py)
+    }
+
+    /// Standard errors, in the same layout as `params`.
+    #[getter]
+    fn errors(&self, py: Python<'_>) -> PyObject {
+        self.fit.errors.clone().into_pyarray(py).to_object(py)
+    }
+
+    #[getter]
+    fn x_axis(&self, py: Python<'_>) -> PyObject {
+        self.fit.x_axis.clone().into_pyarray(py).to_object(py)
+    }
+
+    /// Termination reason per pixel, see `status_names`.
+    #[getter]
+    fn status(&self, py: Python<'_>) -> PyObject {
+        self.fit.status.clone().into_pyarray(py).to_object(py)
+    }
+
+    #[getter]
+    fn success(&self, py: Python<'_>) -> PyObject {
+        self.fit.success_mask().into_pyarray(py).to_object(py)
+    }
+
+    #[getter]
+    fn n_evaluations(&self, py: Python<'_>) -> PyObject {
+        self.fit
+            .n_evaluations
+            .clone()
+            .into_pyarray(py)
+            .to_object(py)
+    }
+
+    #[getter]
+    fn reduced_chi2(&self, py: Python<'_>) -> PyObject {
+        self.fit.reduced_chi2.clone().into_pyarray(py).to_object(py)
+    }
+
+    #[getter]
+    fn r_squared(&self, py: Python<'_>) -> PyObject {
+        self.fit.r_squared.clone().into_pyarray(py).to_object(py)
+    }
+
+    #[getter]
+    fn rms(&self, py: Python<'_>) -> PyObject {
+        self.fit.rms.clone().into_pyarray(py).to_object(py)
+    }
+
+    #[staticmethod]
+    fn status_names() -> Vec<&'static str> {
+        FitStatus::NAMES.to_vec()
+    }
+
+    /// Map of the parameter called `name`.
+    fn __getitem__(&self, name: &str, py: Python<'_>) -> PyResult<PyObject> {
+        let k = self.checked_index(name)?;
+        let map = self.fit.params.index_axis(Axis(2), k).to_owned();
+        Ok(map.into_pyarray(py).to_object(py))
+    }
+
+    /// Standard error map of the parameter called `name`.
+    fn error(&self, name: &str, py: Python<'_>) -> PyResult<PyObject> {
+        let k = self.checked_index(name)?;
+        let map = self.fit.errors.index_axis(Axis(2), k).to_owned();
+        Ok(map.into_pyarray(py).to_object(py))
+    }
+
+    fn to_dict(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let params = PyDict::new(py);
+        let errors = PyDict::new(py);
+        let units = PyDict::new(py);
+        for (k, name) in self.param_names.iter().enumerate() {
+            let value = self.fit.params.index_axis(Axis(2), k).to_owned();
+            let error = self.fit.errors.index_axis(Axis(2), k).to_owned();
+            params.set_item(name, value.into_pyarray(py))?;
+            errors.set_item(name, error.into_pyarray(py))?;
+            units.set_item(name, &self.units[k])?;
+        }
+        let dict = PyDict::new(py);
+        dict.set_item("model", &self.model)?;
+        dict.set_item("param_names", &self.param_names)?;
+        dict.set_item("units", units)?;
+        dict.set_item("params", params)?;
+        dict.set_item("errors", errors)?;
+        dict.set_item("x_axis", self.x_axis(py))?;
+        dict.set_item("status", self.status(py))?;
+        dict.set_item("status_names", Self::status_names())?;
+        dict.set_item("success", self.success(py))?;
+        dict.set_item("n_evaluations", self.n_evaluations(py))?;
+        dict.set_item("reduced_chi2", self.reduced_chi2(py))?;
+        dict.set_item("r_squared", self.r_squared(py))?;
+        dict.set_item("rms", self.rms(py))?;
+        Ok(dict.to_object(py))
+    }
+
+    fn __repr__(&self) -> String {
+        self.to_string()
+    }
+}
+
+#[pymethods]
+impl Metadata {
+    fn __repr__(&self) -> String {
+        format!(
+            "Metadata(path={:?}, experiment_type={:?}, dynamic_steps={:?}, sweeps={:?})",
+            self.path, self.experiment_type, self.dynamic_steps, self.sweeps
+        )
+    }
+}
@@ -0,0 +1,540 @@
+use crate::error::QufitError;
+use crate::fit_common::ImageFit;
+use crate::fit_result::FitResult;
+use crate::load::DataContainer;
+use crate::metadata::Metadata;
+use crate::pipeline::Pipeline;
+use ndarray::{stack, Array, Array1, Array2, ArrayView2, Axis, Dimension};
+use ndarray_npy::{read_npy, write_npy, NpzReader, NpzWriter, ReadableElement, WritableElement};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+const HEADER: &str = "header";
+/// Per-pixel diagnostics saved next to the parameter maps of a fit result.
+const DIAGNOSTICS: [&str; 5] = [
+    "status",
+    "n_evaluations",
+    "reduced_chi2",
+    "r_squared",
+    "rms",
+];
+
+/// What a saved file contains.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Content {
+    Data,
+    FitResult,
+}
+
+/// JSON header written next to the arrays of saved data and fit results.
+/// For fit results, `axes` holds the sweep axis the traces were fitted
+/// against.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Header {
+    pub content: Content,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub param_names: Vec<String>,
+    #[serde(default)]
+    pub units: Vec<String>,
+    #[serde(default)]
+    pub dim_names: Vec<String>,
+    /// Coordinates along every dimension, `null` if unknown.
+    #[serde(default)]
+    pub axes: Vec<Option<Vec<f64>>>,
+    /// Unit of the coordinates along every dimension, `null` if unknown.
+    #[serde(default)]
+    pub axis_units: Vec<Option<String>>,
+    #[serde(default)]
+    pub history: Pipeline,
+    /// The QuPyt metadata of the measurement, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Value>,
+}
+
+/// A folder with one .npy file per array and `header.json`, or a single
+/// .npz file with the header stored as the UTF-8 bytes of `header`.
+enum Writer {
+    Folder(PathBuf),
+    Npz(PathBuf, NpzWriter<File>),
+}
+
+impl Writer {
+    fn folder(dir: &Path) -> Result<Self, QufitError> {
+        fs::create_dir_all(dir)
+            .map_err(|e| QufitError::Io(format!("Could not create {}: {}", dir.display(), e)))?;
+        Ok(Writer::Folder(dir.to_path_buf()))
+    }
+
+    fn npz(path: &Path) -> Result<Self, QufitError> {
+        let file = File::create(path)
+            .map_err(|e| QufitError::Io(format!("Could not create {}: {}", path.display(), e)))?;
+        Ok(Writer::Npz(
+            path.to_path_buf(),
+            NpzWriter::new_compressed(file),
+        ))
+    }
+
+    fn add<A: WritableElement, D: Dimension>(
+        &mut self,
+        name: &str,
+        array: &Array<A, D>,
+    ) -> Result<(), QufitError> {
+        match self {
+            Writer::Folder(dir) => {
+                let path = dir.join(format!("{}.npy", name));
+                write_npy(&path, array).map_err(|e| {
+                    QufitError::Io(format!("Could not write {}: {}", path.display(), e))
+                })
+            }
+            Writer::Npz(path, npz) => npz
+                .add_array(format!("{}.npy", name), array)
+                .map_err(|e| QufitError::Io(format!("Could not write {}: {}", path.display(), e))),
+        }
+    }
+
+    fn finish(self, header: &Header) -> Result<(), QufitError> {
+        let json = serde_json::to_string_pretty(header)
+            .map_err(|e| QufitError::Value(format!("Could not encode the header: {}", e)))?;
+        match self {
+            Writer::Folder(dir) => {
+                let path = dir.join(format!("{}.json", HEADER));
+                fs::write(&path, json).map_err(|e| {
+                    QufitError::Io(format!("Could not write {}: {}", path.display(), e))
+                })
+            }
+            Writer::Npz(path, mut npz) => npz
+                .add_array(
+                    format!("{}.npy", HEADER),
+                    &Array1::from_vec(json.into_bytes()),
+                )
+                .map_err(|e| e.to_string())
+                .and_then(|_| npz.finish().map_err(|e| e.to_string()))
+                .map(|_| ())
+                .map_err(|e| QufitError::Io(format!("Could not write {}: {}", path.display(), e))),
+        }
+    }
+}
+
+/// Reads what [`Writer`] wrote. A path ending in `.npz` is read as a
+/// bundle, anything else as a folder.
+enum Reader {
+    Folder(PathBuf),
+    Npz(PathBuf, NpzReader<File>),
+}
+
+impl Reader {
+    fn open(path: &Path) -> Result<Self, QufitError> {
+        if path.extension().and_then(|ext| ext.to_str()) != Some("npz") {
+            return Ok(Reader::Folder(path.to_path_buf()));
+        }
+        let file = File::open(path)
+            .map_err(|e| QufitError::Io(format!("Could not read {}: {}", path.display(), e)))?;
+        let npz = NpzReader::new(file)
+            .map_err(|e| QufitError::Io(format!("Could not read {}: {}", path.display(), e)))?;
+        Ok(Reader::Npz(path.to_path_buf(), npz))
+    }
+
+    fn contains(&mut self, name: &str) -> bool {
+        match self {
+            Reader::Folder(dir) => dir.join(format!("{}.npy", name)).exists(),
+            Reader::Npz(_, npz) => npz
+                .names()
+                .is_ok_and(|names| names.iter().any(|entry| *entry == format!("{}.npy", name))),
+        }
+    }
+
+    fn get<A: ReadableElement, D: Dimension>(
+        &mut self,
+        name: &str,
+    ) -> Result<Array<A, D>, QufitError> {
+        match self {
+            Reader::Folder(dir) => {
+                let path = dir.join(format!("{}.npy", name));
+                read_npy(&path).map_err(|e| {
+                    QufitError::Io(format!("Could not read {}: {}", path.display(), e))
+                })
+            }
+            Reader::Npz(path, npz) => npz.by_name(&format!("{}.npy", name)).map_err(|e| {
+                QufitError::Io(format!(
+                    "Could not read {} from {}: {}",
+                    name,
+                    path.display(),
+                    e
+                ))
+            }),
+        }
+    }
+
+    fn header(&mut self, expected: Content) -> Result<Header, QufitError> {
+        let (json, source) = match self {
+            Reader::Folder(dir) => {
+                let path = dir.join(format!("{}.json", HEADER));
+                let json = fs::read_to_string(&path).map_err(|e| {
+                    QufitError::Io(format!("Could not read {}: {}", path.display(), e))
+                })?;
+                (json, path)
+            }
+            Reader::Npz(path, _) => {
+                let path = path.clone();
+                let bytes: Array1<u8> = self.get(HEADER)?;
+                let json = String::from_utf8(bytes.to_vec()).map_err(|e| {
+                    QufitError::Io(format!("Could not read {}: {}", path.display(), e))
+                })?;
+                (json, path)
+            }
+        };
+        let header: Header = serde_json::from_str(&json).map_err(|e| {
+            QufitError::Io(format!(
+                "Could not parse the header of {}: {}",
+                source.display(),
+                e
+            ))
+        })?;
+        if header.content != expected {
+            return Err(QufitError::Value(format!(
+                "{} contains {:?}, not {:?}",
+                source.display(),
+                header.content,
+                expected
+            )));
+        }
+        Ok(header)
+    }
+}
+
+impl DataContainer {
+    /// Writes the data, `sigma` if known and a JSON header with the axes,
+    /// the processing history and the metadata to the folder `dir`.
+    pub fn save_npy(&self, dir: &Path) -> Result<(), QufitError> {
+        self.save(Writer::folder(dir)?)
+    }
+
+    /// Like [`DataContainer::save_npy`], bundled into one .npz file.
+    pub fn save_npz(&self, path: &Path) -> Result<(), QufitError> {
+        self.save(Writer::npz(path)?)
+    }
+
+    fn save(&self, mut writer: Writer) -> Result<(), QufitError> {
+        writer.add("data", &self.data)?;
+        if let Some(sigma) = &self.sigma {
+            writer.add("sigma", sigma)?;
+        }
+        writer.finish(&Header {
+            content: Content::Data,
+            model: None,
+            param_names: Vec::new(),
+            units: Vec::new(),
+            dim_names: self.dim_names.clone(),
+            axes: self
+                .axes
+                .iter()
+                .map(|axis| axis.as_ref().map(Array1::to_vec))
+                .collect(),
+            axis_units: self.axis_units.clone(),
+            history: self.history.clone(),
+            metadata: encoded(self.metadata.as_ref())?,
+        })
+    }
+
+    /// Reads data written by [`DataContainer::save_npy`] (a folder) or
+    /// [`DataContainer::save_npz`] (a .npz file).
+    pub fn load_saved(path: &Path) -> Result<Self, QufitError> {
+        let mut reader = Reader::open(path)?;
+        let header = reader.header(Content::Data)?;
+        let data: Array<f64, _> = reader.get("data")?;
+        for (field, len) in [
+            ("dim_names", header.dim_names.len()),
+            ("axes", header.axes.len()),
+        ] {
+            if len != data.ndim() {
+                return Err(QufitError::Shape(format!(
+                    "The header of {} lists {} {}, but the data has {} dimensions",
+                    path.display(),
+                    len,
+                    field,
+                    data.ndim()
+                )));
+            }
+        }
+        let sigma = match reader.contains("sigma") {
+            true => Some(reader.get("sigma")?),
+            false => None,
+        };
+        let mut container = DataContainer::from_data(data);
+        for (dim, axis) in header.axes.into_iter().enumerate() {
+            if let Some(axis) = axis {
+                let unit = header.axis_units.get(dim).cloned().flatten();
+                container.set_axis(dim, Array1::from_vec(axis), unit.as_deref())?;
+            }
+        }
+        container.set_sigma(sigma)?;
+        container.dim_names = header.dim_names;
+        container.history = header.history;
+        container.metadata = header
+            .metadata
+            .map(|raw| Metadata::from_value(path.display().to_string(), raw));
+        Ok(container)
+    }
+}
+
+impl FitResult {
+    /// Writes one map per parameter, its standard error as `<name>_error`,
+    /// the per-pixel diagnostics and a JSON header with the model, the
+    /// units, the sweep axis, the processing history and the metadata to
+    /// the folder `dir`. Fails if two maps would get the same name.
+    pub fn save_npy(&self, dir: &Path) -> Result<(), QufitError> {
+        self.check_map_names()?;
+        self.save(Writer::folder(dir)?)
+    }
+
+    /// Like [`FitResult::save_npy`], bundled into one .npz file.
+    pub fn save_npz(&self, path: &Path) -> Result<(), QufitError> {
+        self.check_map_names()?;
+        self.save(Writer::npz(path)?)
+    }
+
+    fn save(&self, mut writer: Writer) -> Result<(), QufitError> {
+        let fit = &self.fit;
+        for (k, name) in self.param_names.iter().enumerate() {
+            writer.add(name, &fit.params.index_axis(Axis(2), k).to_owned())?;
+            let error = fit.errors.index_axis(Axis(2), k).to_owned();
+            writer.add(&format!("{}_error", name), &error)?;
+        }
+        writer.add("status", &fit.status)?;
+        writer.add("n_evaluations", &fit.n_evaluations.mapv(|n| n as u64))?;
+        writer.add("reduced_chi2", &fit.reduced_chi2)?;
+        writer.add("r_squared", &fit.r_squared)?;
+        writer.add("rms", &fit.rms)?;
+        writer.finish(&Header {
+            content: Content::FitResult,
+            model: Some(self.model.clone()),
+            param_names: self.param_names.clone(),
+            units: self.units.clone(),
+            dim_names: vec!["sweep".to_string()],
+            axes: vec![Some(fit.x_axis.to_vec())],
+            axis_units: vec![self.axis_unit.clone()],
+            history: self.history.clone(),
+            metadata: encoded(self.metadata.as_ref())?,
+        })
+    }
+
+    /// Parameter maps share one namespace with their error maps, the
+    /// diagnostics and the header, so a parameter called `rms`, or two
+    /// called `a` and `a_error`, would overwrite another map.
+    fn check_map_names(&self) -> Result<(), QufitError> {
+        let mut names: Vec<String> = DIAGNOSTICS
+            .iter()
+            .chain([&HEADER])
+            .map(|name| name.to_string())
+            .collect();
+        for name in &self.param_names {
+            for map in [name.clone(), format!("{}_error", name)] {
+                if names.contains(&map) {
+                    return Err(QufitError::Value(format!(
+                        "Cannot save {}: the map '{}' of parameter '{}' clashes with another map",
+                        self.model, map, name
+                    )));
+                }
+                names.push(map);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a result written by [`FitResult::save_npy`] (a folder) or
+    /// [`FitResult::save_npz`] (a .npz file).
+    pub fn load(path: &Path) -> Result<Self, QufitError> {
+        let mut reader = Reader::open(path)?;
+        let header = reader.header(Content::FitResult)?;
+        let mut params: Vec<Array2<f64>> = Vec::new();
+        let mut errors: Vec<Array2<f64>> = Vec::new();
+        for name in &header.param_names {
+            params.push(reader.get(name)?);
+            errors.push(reader.get(&format!("{}_error", name))?);
+        }
+        let stacked = |maps: &[Array2<f64>]| {
+            let views: Vec<ArrayView2<f64>> = maps.iter().map(|map| map.view()).collect();
+            stack(Axis(2), &views).map_err(|e| {
+                QufitError::Shape(format!(
+                    "The maps in {} differ in shape: {}",
+                    path.display(),
+                    e
+                ))
+            })
+        };
+        let evaluations: Array2<u64> = reader.get("n_evaluations")?;
+        let x_axis = header.axes.first().cloned().flatten().unwrap_or_default();
+        let metadata = header
+            .metadata
+            .map(|raw| Metadata::from_value(path.display().to_string(), raw));
+        Ok(FitResult {
+            model: header.model.unwrap_or_default(),
+            param_names: header.param_names,
+            units: header.units,
+            axis_unit: header.axis_units.first().cloned().flatten(),
+            fit: ImageFit {
+                x_axis: Array1::from_vec(x_axis),
+                params: stacked(&params)?,
+                errors: stacked(&errors)?,
+                status: reader.get("status")?,
+                n_evaluations: evaluations.mapv(|n| n as usize),
+                reduced_chi2: reader.get("reduced_chi2")?,
+                r_squared: reader.get("r_squared")?,
+                rms: reader.get("rms")?,
+            },
+            history: header.history,
+            metadata,
+        })
+    }
+}
+
+/// The metadata as it goes into a [`Header`].
+fn encoded(metadata: Option<&Metadata>) -> Result<Option<Value>, QufitError> {
+    metadata
+        .map(|metadata| {
+            serde_json::from_str(&metadata.raw)
+                .map_err(|e| QufitError::Value(format!("Could not encode the metadata: {}", e)))
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fit_esr_nalgebra::Lorentzian;
+    use crate::fit_result::{named, ParamUnit};
+    use crate::load::FitOptions;
+    use crate::pipeline::Step;
+    use ndarray::{s, Array5};
+    use std::env;
+
+    fn scratch(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("qufit_save_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&path);
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_data_round_trip() {
+        let data = Array::from_shape_fn((2, 3, 1, 2, 2), |(r, k, _, i, j)| {
+            100.0 + (r * 7 + k * 3 + i + j) as f64
+        });
+        let mut container = DataContainer::from_data(data.into_dyn());
+        container
+            .set_axis(1, Array1::from_vec(vec![2.8, 2.9, 3.0]), Some("GHz"))
+            .unwrap();
+        container.reference_ratio().unwrap();
+        for path in [scratch("data"), scratch("data.npz")] {
+            match path.extension() {
+                Some(_) => container.save_npz(&path).unwrap(),
+                None => container.save_npy(&path).unwrap(),
+            }
+            let loaded = DataContainer::load(path.to_str().unwrap()).unwrap();
+            assert_eq!(loaded.data, container.data);
+            assert_eq!(loaded.sigma, container.sigma);
+            assert_eq!(loaded.axes, container.axes);
+            assert_eq!(loaded.axis_units, container.axis_units);
+            assert_eq!(loaded.dim_names, container.dim_names);
+            assert_eq!(loaded.history.steps, [Step::ReferenceRatio]);
+            assert!(matches!(FitResult::load(&path), Err(QufitError::Value(_))));
+        }
+    }
+
+    #[test]
+    fn test_fit_result_round_trip() {
+        let mut data = Array5::zeros((1, 21, 1, 2, 3));
+        let x = Array1::linspace(0.0, 1.0, 21);
+        for (n, mut trace) in data
+            .slice_mut(s![0, .., 0, .., ..])
+            .lanes_mut(Axis(0))
+            .into_iter()
+            .enumerate()
+        {
+            let x0 = 0.4 + 0.05 * n as f64;
+            trace.assign(&x.mapv(|x| 1.0 - 0.01 / (1.0 + ((x - x0) / 0.05).powi(2))));
+        }
+        let mut container = DataContainer::from_data(data.into_dyn());
+        container.set_axis(1, x, Some("GHz")).unwrap();
+        let raw = serde_json::json!({"experiment_type": "ODMR", "averages": 100});
+        container.metadata = Some(Metadata::from_value("odmr.yaml".to_string(), raw));
+        let result = container
+            .fit(&Lorentzian, 1, None, &FitOptions::default())
+            .unwrap();
+        assert_eq!(result.axis_unit.as_deref(), Some("GHz"));
+        for path in [scratch("fit"), scratch("fit.npz")] {
+            match path.extension() {
+                Some(_) => result.save_npz(&path).unwrap(),
+                None => result.save_npy(&path).unwrap(),
+            }
+            let loaded = FitResult::load(&path).unwrap();
+            assert_eq!(loaded.model, result.model);
+            assert_eq!(loaded.param_names, result.param_names);
+            assert_eq!(loaded.units, result.units);
+            assert_eq!(loaded.axis_unit, result.axis_unit);
+            let metadata = loaded.metadata.unwrap();
+            assert_eq!(metadata.experiment_type.as_deref(), Some("ODMR"));
+            assert_eq!(metadata.raw, result.metadata.as_ref().unwrap().raw);
+            assert_eq!(loaded.fit.x_axis, result.fit.x_axis);
+            assert_eq!(loaded.fit.params, result.fit.params);
+            assert_eq!(loaded.fit.errors, result.fit.errors);
+            assert_eq!(loaded.fit.status, result.fit.status);
+            assert_eq!(loaded.fit.n_evaluations, result.fit.n_evaluations);
+            assert_eq!(loaded.fit.rms, result.fit.rms);
+        }
+    }
+
+    #[test]
+    fn test_clashing_map_names_are_rejected() {
+        let result = |names: &[&str]| {
+            let params: Vec<_> = names.iter().map(|&name| (name, ParamUnit::None)).collect();
+            FitResult::new(
+                "test",
+                named(&params),
+                None,
+                ImageFit {
+                    x_axis: Array1::zeros(0),
+                    params: Array::zeros((1, 1, names.len())),
+                    errors: Array::zeros((1, 1, names.len())),
+                    status: Array2::zeros((1, 1)),
+                    n_evaluations: Array2::zeros((1, 1)),
+                    reduced_chi2: Array2::zeros((1, 1)),
+                    r_squared: Array2::zeros((1, 1)),
+                    rms: Array2::zeros((1, 1)),
+                },
+            )
+        };
+        for names in [&["rms"][..], &["a", "a_error"], &["a", "a"], &["header"]] {
+            let path = scratch("clash");
+            assert!(matches!(
+                result(names).save_npy(&path),
+                Err(QufitError::Value(_))
+            ));
+            assert!(!path.exists());
+        }
+        let path = scratch("no_clash.npz");
+        result(&["a", "b"]).save_npz(&path).unwrap();
+        assert_eq!(FitResult::load(&path).unwrap().param_names, ["a", "b"]);
+    }
+
+    #[test]
+    fn test_header_dimensions_are_checked() {
+        let container = DataContainer::from_data(Array::zeros((2, 3, 1, 2, 2)).into_dyn());
+        let path = scratch("dims");
+        container.save_npy(&path).unwrap();
+        let header_path = path.join("header.json");
+        let mut header: Header =
+            serde_json::from_str(&fs::read_to_string(&header_path).unwrap()).unwrap();
+        header.dim_names.pop();
+        fs::write(&header_path, serde_json::to_string(&header).unwrap()).unwrap();
+        match DataContainer::load_saved(&path) {
+            Err(QufitError::Shape(message)) => assert!(message.contains("lists 4 dim_names")),
+            other => panic!("expected a shape error, got {:?}", other.map(|_| ())),
+        }
+    }
+}